@@ -0,0 +1,327 @@
+// Conversion of Azure Functions 2019 traces into requests for `controller import-azure`.
+//
+// The trace has per-minute invocation counts of each function
+// (invocations_per_function_md.anon.dNN.csv) and, optionally, their durations
+// (function_durations_percentiles.anon.dNN.csv) and the memory of their apps
+// (app_memory_percentiles.anon.dNN.csv). Each trace function is mapped onto a configured
+// function, by memory class or round-robin, and a user of it. Its invocations are spread
+// uniformly at random over their minute.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde_json::json;
+
+use super::config::Configuration;
+use super::request::Request;
+
+const MINUTE_MS: f64 = 60_000.0;
+
+// How many malformed rows of a CSV are reported before the rest are only counted
+const MAX_REPORTED_MALFORMED: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mapping {
+    Memory,     // the smallest configured function with at least the app's memory
+    RoundRobin, // the configured function that got the fewest trace functions so far
+}
+
+// A function of the trace
+struct TraceFunction {
+    function: String,           // configured function it is mapped onto
+    user_id: u32,
+    duration_ms: Option<f64>,   // average
+    counts: Vec<u64>,           // invocations per minute
+}
+
+// Columns of a CSV line. The trace has no quoted fields.
+fn fields(line: &str) -> Vec<&str> {
+    line.trim_end().split(',').collect()
+}
+
+// The rows of a CSV, with its columns by name. Rows that are shorter than the header, or that
+// `parse` rejects, are skipped and counted. Fails on a missing column or a read error.
+fn rows<R, T, F>(csv: R, name: &str, required: &[&str], mut parse: F) -> Result<Vec<T>, String>
+    where R: BufRead, F: FnMut(&[&str], &BTreeMap<String, usize>) -> Result<T, String>
+{
+    let mut lines = csv.lines();
+    let line = lines.next().ok_or(format!("empty {} CSV", name))?.map_err(|e| e.to_string())?;
+    let columns: BTreeMap<String, usize> = fields(&line).iter().enumerate()
+        .map(|(i, name)| (name.to_string(), i))
+        .collect();
+    for column in required {
+        if !columns.contains_key(*column) {
+            return Err(format!("{} CSV has no {} column", name, column));
+        }
+    }
+
+    let mut rows = Vec::new();
+    let mut malformed = 0;
+    for (i, line) in lines.enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let f = fields(&line);
+        let row = if f.len() < columns.len() {
+            Err(format!("{} of {} columns", f.len(), columns.len()))
+        } else {
+            parse(&f, &columns)
+        };
+        match row {
+            Ok(row) => rows.push(row),
+            Err(e) => {
+                malformed += 1;
+                if malformed <= MAX_REPORTED_MALFORMED {
+                    eprintln!("Skipping malformed line {} of {} CSV: {}", i + 2, name, e);
+                }
+            },
+        }
+    }
+    if malformed > MAX_REPORTED_MALFORMED {
+        eprintln!("Skipped {} malformed lines of {} CSV", malformed, name);
+    }
+    Ok(rows)
+}
+
+// Average allocated memory of each (owner, app)
+pub fn read_memory<R: BufRead>(csv: R) -> Result<BTreeMap<(String, String), f64>, String> {
+    let rows = rows(csv, "memory", &["HashOwner", "HashApp", "AverageAllocatedMb"], |f, columns| {
+        let mb = f[columns["AverageAllocatedMb"]].parse::<f64>()
+            .map_err(|e| format!("invalid memory: {}", e))?;
+        Ok(((f[columns["HashOwner"]].to_string(), f[columns["HashApp"]].to_string()), mb))
+    })?;
+    Ok(rows.into_iter().collect())
+}
+
+// Average duration in ms of each (owner, app, function)
+pub fn read_durations<R: BufRead>(csv: R) -> Result<BTreeMap<(String, String, String), f64>, String> {
+    let rows = rows(csv, "durations", &["HashOwner", "HashApp", "HashFunction", "Average"], |f, columns| {
+        let ms = f[columns["Average"]].parse::<f64>()
+            .map_err(|e| format!("invalid duration: {}", e))?;
+        Ok(((f[columns["HashOwner"]].to_string(), f[columns["HashApp"]].to_string(),
+             f[columns["HashFunction"]].to_string()), ms))
+    })?;
+    Ok(rows.into_iter().collect())
+}
+
+pub struct Importer<'a> {
+    configs: &'a Configuration,
+    mapping: Mapping,
+    memory: BTreeMap<(String, String), f64>,
+    durations: BTreeMap<(String, String, String), f64>,
+    mapped: BTreeMap<String, u32>, // how many trace functions each configured function got
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(configs: &'a Configuration, mapping: Mapping, memory: BTreeMap<(String, String), f64>,
+               durations: BTreeMap<(String, String, String), f64>) -> Importer<'a> {
+        Importer {
+            configs,
+            mapping,
+            memory,
+            durations,
+            mapped: BTreeMap::new(),
+        }
+    }
+
+    // Pick the configured function and user of a trace function of `app`. Trace functions are
+    // spread evenly over the candidate functions and their users.
+    fn map(&mut self, app: &(String, String)) -> Result<(String, u32), String> {
+        let configs: &'a Configuration = self.configs;
+        let mut candidates: Vec<&String> = configs.configs.keys().collect();
+        if let (Mapping::Memory, Some(&mb)) = (self.mapping, self.memory.get(app)) {
+            // the smallest memory class that fits, or the largest if none does
+            let memories = || configs.configs.values().map(|c| c.memory);
+            let class = memories().filter(|&m| m as f64 >= mb).min().or(memories().max());
+            candidates.retain(|name| Some(configs.configs[*name].memory) == class);
+        }
+        let function = candidates.into_iter()
+            .min_by_key(|name| (self.mapped.get(*name).cloned().unwrap_or(0), *name))
+            .ok_or("no functions configured")?
+            .clone();
+
+        let mapped = self.mapped.entry(function.clone()).or_insert(0);
+        let user_id = *mapped % configs.configs[&function].users.max(1);
+        *mapped += 1;
+        Ok((function, user_id))
+    }
+
+    fn read_invocations<R: BufRead>(&mut self, csv: R, max_minutes: Option<usize>) -> Result<Vec<TraceFunction>, String> {
+        if self.configs.configs.is_empty() {
+            return Err("no functions configured".to_string());
+        }
+        let rows = rows(csv, "invocations", &["HashOwner", "HashApp", "HashFunction", "1"], |f, columns| {
+            let app = (f[columns["HashOwner"]].to_string(), f[columns["HashApp"]].to_string());
+            let function = f[columns["HashFunction"]].to_string();
+            let mut counts = f[columns["1"]..].iter()
+                .map(|c| c.parse::<u64>().map_err(|e| format!("invalid count: {}", e)))
+                .collect::<Result<Vec<u64>, String>>()?;
+            if let Some(max) = max_minutes {
+                counts.truncate(max);
+            }
+            Ok((app, function, counts))
+        })?;
+
+        // only valid rows are mapped, in trace order
+        let mut functions = Vec::new();
+        for (app, function, counts) in rows {
+            let key = (app.0.clone(), app.1.clone(), function);
+            let (function, user_id) = self.map(&app)?;
+            functions.push(TraceFunction {
+                duration_ms: self.durations.get(&key).cloned(),
+                function,
+                user_id,
+                counts,
+            });
+        }
+        Ok(functions)
+    }
+
+    // Write the requests of the invocations in `csv` to `out`, with timestamps multiplied by
+    // `time_scale`. Returns how many there were.
+    pub fn import<R: BufRead, W: Write>(&mut self, csv: R, out: W, time_scale: f64, seed: u64,
+                                        max_minutes: Option<usize>) -> Result<u64, String> {
+        let functions = self.read_invocations(csv, max_minutes)?;
+        let minutes = functions.iter().map(|f| f.counts.len()).max().unwrap_or(0);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut out = io::BufWriter::new(out);
+        let mut count = 0;
+
+        // a minute at a time, so memory only depends on the busiest minute
+        for minute in 0..minutes {
+            let mut requests = Vec::new();
+            for (i, function) in functions.iter().enumerate() {
+                for _ in 0..function.counts.get(minute).cloned().unwrap_or(0) {
+                    let ms = (minute as f64 + rng.gen::<f64>()) * MINUTE_MS * time_scale;
+                    requests.push((ms as u64, i));
+                }
+            }
+            requests.sort();
+            for (timestamp, i) in requests {
+                let function = &functions[i];
+                let payload = match function.duration_ms {
+                    Some(duration) => json!({"request": 42, "duration_ms": duration}),
+                    None => json!({"request": 42}),
+                };
+                let request = Request {
+                    timestamp,
+                    function: function.function.clone(),
+                    payload,
+                    user_id: function.user_id,
+                    id: None,
+                };
+                serde_json::to_writer(&mut out, &request).map_err(|e| e.to_string())?;
+                out.write_all(b"\n").map_err(|e| e.to_string())?;
+                count += 1;
+            }
+        }
+        out.flush().map_err(|e| e.to_string())?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use super::*;
+    use super::super::request;
+
+    const FUNCTIONS: &str = "
+- {name: small, runtimefs: none, appfs: none, runtime: none, users: 2, vcpus: 1, memory: 128, concurrency_limit: 10}
+- {name: big, runtimefs: none, appfs: none, runtime: none, users: 1, vcpus: 1, memory: 512, concurrency_limit: 10}
+";
+
+    fn fixture(name: &str) -> BufReader<File> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../workloads/azure-sample/");
+        BufReader::new(File::open(format!("{}{}", dir, name)).unwrap())
+    }
+
+    fn import(configs: &Configuration, mapping: Mapping, time_scale: f64, max_minutes: Option<usize>)
+              -> Result<Vec<request::Request>, String> {
+        let memory = read_memory(fixture("app_memory_percentiles.anon.d01.csv")).unwrap();
+        let durations = read_durations(fixture("function_durations_percentiles.anon.d01.csv")).unwrap();
+        let mut importer = Importer::new(configs, mapping, memory, durations);
+        let mut out = Vec::new();
+        let count = importer.import(fixture("invocations_per_function_md.anon.d01.csv"), &mut out,
+                                    time_scale, 0, max_minutes)?;
+        let requests: Vec<request::Request> = String::from_utf8(out).unwrap().lines()
+            .map(|line| request::parse_json(line.to_string()).unwrap())
+            .collect();
+        assert_eq!(requests.len() as u64, count);
+        Ok(requests)
+    }
+
+    #[test]
+    fn reads_memory() {
+        let memory = read_memory(fixture("app_memory_percentiles.anon.d01.csv")).unwrap();
+        assert_eq!(memory.len(), 4);
+        assert_eq!(memory[&("o1".to_string(), "a1".to_string())] as u64, 120);
+        assert_eq!(memory[&("o3".to_string(), "a4".to_string())] as u64, 900);
+    }
+
+    #[test]
+    fn reads_durations() {
+        let durations = read_durations(fixture("function_durations_percentiles.anon.d01.csv")).unwrap();
+        assert_eq!(durations.len(), 6);
+        assert_eq!(durations[&("o1".to_string(), "a2".to_string(), "f3".to_string())] as u64, 80);
+        assert_eq!(durations[&("o3".to_string(), "a4".to_string(), "f6".to_string())] as u64, 1200);
+    }
+
+    #[test]
+    fn skips_malformed_rows() {
+        let csv = "HashOwner,HashApp,SampleCount,AverageAllocatedMb\no1,a1,28,120\no1,a2\no2,a3,82,lots\n\no3,a4,1,900\n";
+        let memory = read_memory(csv.as_bytes()).unwrap();
+        assert_eq!(memory.len(), 2);
+        assert_eq!(memory[&("o3".to_string(), "a4".to_string())] as u64, 900);
+
+        assert!(read_memory("HashOwner,HashApp\no1,a1\n".as_bytes()).is_err());
+        assert!(read_memory("".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn imports_by_memory() {
+        let configs = Configuration::new("", "", FUNCTIONS.as_bytes());
+        let requests = import(&configs, Mapping::Memory, 0.5, None).unwrap();
+        assert_eq!(requests.len(), 189);
+
+        // a1 fits the small function, every other app only the big one
+        let mut per_function = BTreeMap::new();
+        for req in requests.iter() {
+            *per_function.entry(req.function.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(per_function["small"], 26 + 25);
+        assert_eq!(per_function["big"], 189 - 51);
+        // f1 and f2 go to different users of the small function
+        assert!(requests.iter().any(|r| r.function == "small" && r.user_id == 0));
+        assert!(requests.iter().any(|r| r.function == "small" && r.user_id == 1));
+        assert!(requests.iter().all(|r| r.function == "small" || r.user_id == 0));
+
+        assert!(requests.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert!(requests.iter().all(|r| r.timestamp < 10 * 30_000));
+        assert!(requests.iter().all(|r| r.payload["duration_ms"].is_number()));
+    }
+
+    #[test]
+    fn imports_round_robin_and_limits_minutes() {
+        let configs = Configuration::new("", "", FUNCTIONS.as_bytes());
+        let requests = import(&configs, Mapping::RoundRobin, 1.0, Some(2)).unwrap();
+        assert_eq!(requests.len(), 29);
+        assert!(requests.iter().all(|r| r.timestamp < 2 * 60_000));
+        assert!(requests.iter().any(|r| r.function == "small"));
+        assert!(requests.iter().any(|r| r.function == "big"));
+
+        // the same seed gives the same trace
+        let again = import(&configs, Mapping::RoundRobin, 1.0, Some(2)).unwrap();
+        assert!(requests.iter().zip(again.iter()).all(|(a, b)| a.timestamp == b.timestamp && a.function == b.function));
+    }
+
+    #[test]
+    fn fails_without_functions() {
+        let configs = Configuration::new("", "", "[]".as_bytes());
+        assert!(import(&configs, Mapping::RoundRobin, 1.0, None).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+// How the controller launches VMs. `FirecrackerBackend` runs real VMs, `FakeBackend` runs a
+// thread per VM that speaks the same protocol, so the controller can run without KVM, cgroups
+// or root. `SimBackend` VMs only exist in the controller's bookkeeping and are driven by a
+// simulation. `PendingVm` stands in for a VM that is still being launched.
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::poll::{self, PollFd, PollFlags};
+use nix::unistd;
+
+use firerunner::cgroup::Cpuset;
+use firerunner::error::Error;
+use firerunner::pipe_pair::PipePair;
+use firerunner::runner::{VmApp, VmAppConfig};
+
+// A launched VM as far as the controller is concerned
+pub trait VmHandle: Send + Debug {
+    fn connection(&self) -> Option<&PipePair>; // none if the VM only exists in a simulation
+    fn cpuset(&self) -> Option<&Cpuset>;
+    fn pause(&mut self) -> Result<(), Error>;
+    fn resume(&mut self) -> Result<(), Error>;
+    fn kill(&mut self) -> Result<(), Error>;
+    fn wait(&mut self) -> Result<(), Error>;
+}
+
+pub trait VmBackend: Send + Sync {
+    // Start a VM for `config`, once `evict` (if any) is gone. The VM writes its vsock CID to
+    // `config.notifier` when it is ready for requests.
+    fn launch(&self, config: VmAppConfig, evict: Option<Box<VmHandle>>) -> Result<Box<VmHandle>, Error>;
+}
+
+pub struct FirecrackerBackend {
+    pub debug: bool, // whether VMs keep stdout
+}
+
+impl VmBackend for FirecrackerBackend {
+    fn launch(&self, config: VmAppConfig, evict: Option<Box<VmHandle>>) -> Result<Box<VmHandle>, Error> {
+        // dropping the evicted VM kills it and waits for it to exit
+        drop(evict);
+        Ok(Box::new(config.run(self.debug, None)?))
+    }
+}
+
+impl VmHandle for VmApp {
+    fn connection(&self) -> Option<&PipePair> {
+        Some(&self.connection)
+    }
+
+    fn cpuset(&self) -> Option<&Cpuset> {
+        self.config.cgroup.cpuset.as_ref()
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        VmApp::pause(self)
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        VmApp::resume(self)
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        VmApp::kill(self)
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        VmApp::wait(self)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeBackend {
+    pub boot_delay: Duration,      // zero VMs are ready before their launch returns
+    pub latency: Duration,         // how long each request takes
+    pub fail_every: Option<u64>,   // every nth request of a VM fails, closing its connection
+}
+
+impl VmBackend for FakeBackend {
+    fn launch(&self, config: VmAppConfig, evict: Option<Box<VmHandle>>) -> Result<Box<VmHandle>, Error> {
+        if let Some(mut evict) = evict {
+            evict.kill()?;
+            evict.wait()?;
+        }
+        Ok(Box::new(FakeVm::start(self.clone(), config)?))
+    }
+}
+
+#[derive(Debug)]
+pub struct FakeVm {
+    connection: PipePair,
+    cpuset: Option<Cpuset>,
+    kill_writer: Option<File>, // closing it stops the VM thread
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeVm {
+    fn start(backend: FakeBackend, config: VmAppConfig) -> Result<FakeVm, Error> {
+        let id = config.vsock_cid;
+        let mut notifier = Some(config.notifier);
+        if backend.boot_delay == Duration::from_millis(0) {
+            notifier.take().unwrap().write_all(&id.to_le_bytes()).map_err(Error::Io)?;
+        }
+        let (request_reader, request_writer) = unistd::pipe().map_err(Error::Pipe)?;
+        let (response_reader, response_writer) = unistd::pipe().map_err(Error::Pipe)?;
+        let (kill_reader, kill_writer) = unistd::pipe().map_err(Error::Pipe)?;
+        let (request_reader, response_writer, kill_reader) = unsafe {
+            (File::from_raw_fd(request_reader), File::from_raw_fd(response_writer),
+             File::from_raw_fd(kill_reader))
+        };
+
+        let thread = thread::spawn(move || {
+            if let Some(mut notifier) = notifier {
+                if killed(&kill_reader, None, Some(backend.boot_delay)) {
+                    return;
+                }
+                if notifier.write_all(&id.to_le_bytes()).is_err() {
+                    return;
+                }
+            }
+
+            let mut request_reader = request_reader;
+            let mut response_writer = response_writer;
+            let mut pending = Vec::new(); // read, but not yet served
+            let mut served = 0u64;
+            loop {
+                // requests may already be pending, the kill pipe is checked before reading more
+                let end = match pending.iter().position(|&b| b == b'\n') {
+                    Some(end) => end,
+                    None => {
+                        if killed(&kill_reader, Some(&request_reader), None) {
+                            return;
+                        }
+                        let mut buf = [0u8; 4096];
+                        match request_reader.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => pending.extend_from_slice(&buf[..n]),
+                        }
+                        continue;
+                    },
+                };
+                pending.drain(..=end);
+                if killed(&kill_reader, None, Some(backend.latency)) {
+                    return;
+                }
+                served += 1;
+                if backend.fail_every.map(|n| served % n == 0).unwrap_or(false) {
+                    return;
+                }
+                let response = format!("{{\"vm\": {}, \"request\": {}}}", id, served);
+                let mut message = (response.len() as u32).to_be_bytes().to_vec();
+                message.extend_from_slice(response.as_bytes());
+                if response_writer.write_all(&message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(FakeVm {
+            connection: PipePair::new(request_writer, response_reader),
+            cpuset: config.cgroup.cpuset,
+            kill_writer: Some(unsafe { File::from_raw_fd(kill_writer) }),
+            thread: Some(thread),
+        })
+    }
+}
+
+// Wait up to `timeout`, or forever if `None`, for `kill_reader` to be closed or `other` to
+// become readable. Returns whether `kill_reader` was closed.
+fn killed(kill_reader: &File, other: Option<&File>, timeout: Option<Duration>) -> bool {
+    let timeout = timeout.map(|t| t.as_millis() as i32).unwrap_or(-1);
+    let mut fds = vec![PollFd::new(kill_reader.as_raw_fd(), PollFlags::POLLIN)];
+    if let Some(other) = other {
+        fds.push(PollFd::new(other.as_raw_fd(), PollFlags::POLLIN));
+    }
+    match poll::poll(&mut fds, timeout) {
+        Ok(0) => false,
+        Ok(_) => fds[0].revents().map(|events| !events.is_empty()).unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+impl VmHandle for FakeVm {
+    fn connection(&self) -> Option<&PipePair> {
+        Some(&self.connection)
+    }
+
+    fn cpuset(&self) -> Option<&Cpuset> {
+        self.cpuset.as_ref()
+    }
+
+    // fake VMs don't use any CPU while idle anyway
+    fn pause(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        self.kill_writer.take();
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        if let Some(thread) = self.thread.take() {
+            thread.join().map_err(|_| Error::ChildExited)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FakeVm {
+    fn drop(&mut self) {
+        let _ = self.kill();
+        let _ = self.wait();
+    }
+}
+
+pub struct SimBackend;
+
+impl VmBackend for SimBackend {
+    fn launch(&self, config: VmAppConfig, _evict: Option<Box<VmHandle>>) -> Result<Box<VmHandle>, Error> {
+        Ok(Box::new(SimVm { cpuset: config.cgroup.cpuset }))
+    }
+}
+
+#[derive(Debug)]
+pub struct SimVm {
+    cpuset: Option<Cpuset>,
+}
+
+impl VmHandle for SimVm {
+    fn connection(&self) -> Option<&PipePair> {
+        None
+    }
+
+    fn cpuset(&self) -> Option<&Cpuset> {
+        self.cpuset.as_ref()
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// Placeholder for a VM until the booter launched it. It has no connection yet, and there is
+// nothing to pause or kill.
+#[derive(Debug)]
+pub struct PendingVm {
+    pub cpuset: Option<Cpuset>,
+}
+
+impl VmHandle for PendingVm {
+    fn connection(&self) -> Option<&PipePair> {
+        None
+    }
+
+    fn cpuset(&self) -> Option<&Cpuset> {
+        self.cpuset.as_ref()
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
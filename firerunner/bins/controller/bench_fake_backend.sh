@@ -0,0 +1,78 @@
+#!/bin/bash
+# Benchmark the controller itself, with fake VMs, e.g. to compare builds from before and after a
+# change to scheduling:
+#
+#   ./bench_fake_backend.sh before/target/release/controller target/release/controller
+#
+# Every controller replays the same generated trace. For each, prints the cumulative throughput
+# and the median and 99th percentile scheduling latency and dispatch lag.
+#
+# FUNCTIONS, USERS (per function), MU (mean inter-arrival time per user, in ms), DURATION (in ms),
+# BOOT_DELAY and LATENCY (of the fake VMs, in ms) and MEM_SIZE (in MB) tune the workload.
+set -e
+
+if [ $# -eq 0 ]; then
+	echo "usage: $0 CONTROLLER..." >&2
+	exit 1
+fi
+
+functions=${FUNCTIONS:-4}
+users=${USERS:-20}
+mu=${MU:-20}
+duration=${DURATION:-30000}
+boot_delay=${BOOT_DELAY:-100}
+latency=${LATENCY:-10}
+mem_size=${MEM_SIZE:-65536}
+
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+
+echo "start_time: 0" > "$dir/workload.yaml"
+echo "end_time: $duration" >> "$dir/workload.yaml"
+echo "num_users: $users" >> "$dir/workload.yaml"
+echo "functions:" >> "$dir/workload.yaml"
+> "$dir/functions.yaml"
+for f in $(seq 1 $functions)
+do
+	echo "  - {name: \"bench$f\", mu: $mu}" >> "$dir/workload.yaml"
+	cat >> "$dir/functions.yaml" <<END
+- name: "bench$f"
+  runtime: "none"
+  runtimefs: "none"
+  appfs: "none"
+  users: $users
+  vcpus: 1
+  memory: 128
+  concurrency_limit: 1000
+END
+done
+
+"$1" gen-workload "$dir/workload.yaml" -o "$dir/requests.json"
+
+for controller in "$@"
+do
+	echo "$controller"
+	"$controller" --backend fake --fake_boot_delay $boot_delay --fake_latency $latency \
+		--kernel none --cmd_line none --runtimefs_dir "$dir" --appfs_dir "$dir" \
+		--fconfig "$dir/functions.yaml" --requests "$dir/requests.json" \
+		--mem_size $mem_size --output "$dir/output.json" > /dev/null
+
+	python3 - "$dir/output.json" <<'END'
+import json, sys
+
+output = json.load(open(sys.argv[1]))
+
+def percentiles(name):
+    values = sorted(output.get(name) or [])
+    if not values:
+        return "n/a"
+    p = lambda q: values[min(len(values) - 1, int(q * len(values)))] / 1e6
+    return "median %.3f ms, p99 %.3f ms" % (p(0.5), p(0.99))
+
+print("  throughput: %.1f requests/s" % output["cumulative throughput"])
+print("  completed: %d, dropped: %d" % (output["number of completed requests"],
+                                        output["drop requests (resource)"] + output["drop requests (concurrency)"]))
+print("  schedule latency: " + percentiles("request schedule latency"))
+print("  dispatch lag: " + percentiles("dispatch lag"))
+END
+done
@@ -0,0 +1,32 @@
+// Time source of the controller. Real runs use the wall clock, simulations a virtual clock
+// that they advance themselves.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub trait Clock: Send + Sync {
+    fn now_ns(&self) -> u64;
+}
+
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now_ns(&self) -> u64 {
+        time::precise_time_ns()
+    }
+}
+
+// Clones share the same time
+#[derive(Clone, Default)]
+pub struct VirtualClock(Arc<AtomicU64>);
+
+impl VirtualClock {
+    pub fn set(&self, ns: u64) {
+        self.0.store(ns, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now_ns(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,170 @@
+// Closed-loop load generation. Each function gets a number of virtual users that each send a
+// request, wait for its response, think for an exponentially distributed time and repeat,
+// until the run's duration is over. Responses are matched to their users by request id.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde_json::{json, Value};
+
+use super::config::Configuration;
+use super::controller::{Controller, Placement};
+use super::request::Request;
+
+// How long to wait for a response at most before checking for an interrupt
+const POLL_INTERVAL_NS: u64 = 100_000_000;
+
+struct VirtualUser {
+    function: String,
+    user_id: u32, // the function's user it sends requests as
+}
+
+pub struct ClosedLoop {
+    users_per_function: u32,
+    think_ms: f64,     // mean think time
+    duration: Duration,
+    timeout: Duration, // after which a request without a response counts as lost
+    rng: StdRng,
+    issued: u64,
+    dropped: u64,
+    timed_out: u64,
+    response_latency: BTreeMap<String, Vec<u64>>, // in ns, per function
+}
+
+impl ClosedLoop {
+    pub fn new(users_per_function: u32, think_ms: f64, duration: Duration, timeout: Duration, seed: u64) -> ClosedLoop {
+        ClosedLoop {
+            users_per_function,
+            think_ms,
+            duration,
+            timeout,
+            rng: StdRng::seed_from_u64(seed),
+            issued: 0,
+            dropped: 0,
+            timed_out: 0,
+            response_latency: BTreeMap::new(),
+        }
+    }
+
+    fn think_ns(&mut self) -> u64 {
+        (-self.think_ms * (1.0 - self.rng.gen::<f64>()).ln() * 1_000_000.0) as u64
+    }
+
+    // Run the virtual users against `controller`, which must not have been ignited yet.
+    // Returns when the run started and ended.
+    pub fn run(&mut self, controller: &mut Controller, configs: &Configuration,
+               interrupted: &AtomicBool) -> (u64, u64) {
+        let completions = controller.completions();
+        controller.ignite();
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        // the function's users take turns, functions without users get none
+        let mut users = Vec::new();
+        for (function, config) in configs.configs.iter() {
+            for i in 0..self.users_per_function {
+                if config.users > 0 {
+                    users.push(VirtualUser { function: function.clone(), user_id: i % config.users });
+                }
+            }
+        }
+
+        let start = time::precise_time_ns();
+        let end = start + self.duration.as_nanos() as u64;
+        let timeout = self.timeout.as_nanos() as u64;
+
+        // users waiting to send their next request, by when, and requests awaiting a response
+        let mut thinking: BinaryHeap<Reverse<(u64, usize)>> = (0..users.len()).map(|u| Reverse((start, u))).collect();
+        let mut in_flight: BTreeMap<u64, (usize, u64)> = BTreeMap::new();
+        let mut next_id = 0;
+
+        while !interrupted.load(Ordering::SeqCst) {
+            let now = time::precise_time_ns();
+            if now >= end {
+                thinking.clear();
+                if in_flight.is_empty() {
+                    break;
+                }
+            }
+
+            while thinking.peek().map(|&Reverse((at, _))| at <= now).unwrap_or(false) {
+                let Reverse((at, user)) = thinking.pop().unwrap();
+                let req = Request {
+                    timestamp: (now - start) / 1_000_000,
+                    function: users[user].function.clone(),
+                    payload: json!({"request": 42}),
+                    user_id: users[user].user_id,
+                    id: Some(next_id),
+                };
+                self.issued += 1;
+                let lag = time::precise_time_ns().saturating_sub(at);
+                controller.update_stat(move |stat| stat.log_dispatch_lag(lag));
+                match controller.schedule(req) {
+                    Placement::Dropped => {
+                        self.dropped += 1;
+                        let think = self.think_ns();
+                        thinking.push(Reverse((now + think, user)));
+                    },
+                    _ => {
+                        in_flight.insert(next_id, (user, now));
+                    },
+                }
+                next_id += 1;
+            }
+
+            // give up on requests whose VM never answered
+            let lost: Vec<u64> = in_flight.iter()
+                .filter(|&(_, &(_, sent))| sent + timeout <= now)
+                .map(|(&id, _)| id)
+                .collect();
+            for id in lost {
+                let (user, _) = in_flight.remove(&id).unwrap();
+                self.timed_out += 1;
+                let think = self.think_ns();
+                thinking.push(Reverse((now + think, user)));
+            }
+
+            let next_send = thinking.peek().map(|&Reverse((at, _))| at).unwrap_or(std::u64::MAX);
+            let wait = next_send.saturating_sub(now).min(POLL_INTERVAL_NS);
+            match completions.recv_timeout(Duration::from_nanos(wait)) {
+                Ok((id, response)) => {
+                    // a response after the timeout has nobody waiting for it anymore
+                    if let Some((user, sent)) = in_flight.remove(&id) {
+                        let received = time::precise_time_ns();
+                        match response {
+                            Some(_) => {
+                                self.response_latency.entry(users[user].function.clone()).or_insert(Vec::new())
+                                    .push(received - sent);
+                            },
+                            // e.g. the request's VM failed to boot or crashed
+                            None => self.dropped += 1,
+                        }
+                        let think = self.think_ns();
+                        thinking.push(Reverse((received + think, user)));
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        (start, time::precise_time_ns())
+    }
+
+    // Settings and results of the run, for the controller's output
+    pub fn summary(&self) -> Value {
+        json!({
+            "users per function": self.users_per_function,
+            "mean think time": self.think_ms,
+            "duration": self.duration.as_secs(),
+            "issued requests": self.issued,
+            "dropped requests": self.dropped,
+            "timed out requests": self.timed_out,
+            "response latencies": self.response_latency,
+        })
+    }
+}
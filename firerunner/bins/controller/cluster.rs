@@ -1,14 +1,27 @@
 // This module represents states of the physical cluster.
-// The states currently tracked are cpu and memory
+// The states currently tracked are cpu and memory. Each machine also hands out disjoint sets
+// of logical CPUs to VMs that run with dedicated CPUs.
 extern crate num_cpus;
 
-use std::fs::File;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
 use std::io::{BufReader, BufRead};
 
+use firerunner::cgroup::{self, Cpuset};
+
 const MEM_FILE: &str = "/proc/meminfo";     // meminfo file on linux
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+const NODE_DIR: &str = "/sys/devices/system/node";
 const KB_IN_MB: usize = 1024;
 const MEM_4G: usize = 4096;  // in MB
 
+// A physical core: its hyperthreads and the NUMA node it belongs to
+#[derive(Clone, Debug)]
+struct Core {
+    cpus: Vec<usize>,
+    node: usize,
+}
+
 #[derive(Clone, Debug)]
 pub struct MachineInfo {
     id: String,
@@ -17,6 +30,8 @@ pub struct MachineInfo {
 //    total_storage: u32,   // amount of storage (MB)
     free_cpu: u64,
     free_mem: usize,
+    cores: Vec<Core>,
+    free_cpu_set: BTreeSet<usize>, // logical CPUs not dedicated to a VM
 }
 
 #[derive(Clone, Debug)]
@@ -62,12 +77,15 @@ impl Cluster{
             mem = mem / KB_IN_MB - MEM_4G;
         }
 
+        let cores = MachineInfo::read_topology(cpus as usize);
         let mc = MachineInfo{
             id: String::from("1"),
             total_cpu: cpus,
             total_mem: mem,
             free_cpu: cpus,
             free_mem: mem,
+            free_cpu_set: cores.iter().flat_map(|c| c.cpus.iter().cloned()).collect(),
+            cores,
         };
 
         Cluster{
@@ -80,11 +98,12 @@ impl Cluster{
         }
     }
 
-    // Find a machine in the cluster that has enough resources to boot a new VM for a function
-    pub fn find_free_machine(&self, req_mem: usize) -> Option<(u32, &MachineInfo)> {
+    // Find a machine in the cluster that has enough resources to boot a new VM for a function.
+    // `req_cpus` is the number of dedicated CPUs the VM needs, 0 if it runs on shared CPUs.
+    pub fn find_free_machine(&self, req_mem: usize, req_cpus: usize) -> Option<(u32, &MachineInfo)> {
 
         for (i, m) in self.host_list.iter().enumerate() {
-            if m.free_mem >= req_mem {
+            if m.free_mem >= req_mem && m.can_allocate_cpus(req_cpus, None) {
                 return Some((i as u32,m));
             }
         }
@@ -101,6 +120,22 @@ impl Cluster{
         self.host_list.get_mut(id as usize).unwrap().free(mem);
     }
 
+    // Whether `req_cpus` dedicated CPUs would be available on a machine once `freed` is released
+    pub fn can_allocate_cpus(&self, id: u32, req_cpus: usize, freed: Option<&Cpuset>) -> bool {
+        self.host_list[id as usize].can_allocate_cpus(req_cpus, freed)
+    }
+
+    pub fn allocate_cpus(&mut self, id: u32, req_cpus: usize) -> Option<Cpuset> {
+        let cpuset = self.host_list.get_mut(id as usize).unwrap().allocate_cpus(req_cpus)?;
+        self.total_free_cpu = self.total_free_cpu - req_cpus as u64;
+        Some(cpuset)
+    }
+
+    pub fn free_cpus(&mut self, id: u32, cpuset: &Cpuset) {
+        self.total_free_cpu = self.total_free_cpu + cpuset.cpus.len() as u64;
+        self.host_list.get_mut(id as usize).unwrap().free_cpus(cpuset);
+    }
+
 }
 
 impl MachineInfo {
@@ -111,4 +146,96 @@ impl MachineInfo {
     pub fn free(&mut self, req_mem: usize) {
         self.free_mem = self.free_mem + req_mem;
     }
+
+    // Read which hyperthreads share a physical core and which NUMA node each core is on.
+    // Without sysfs every logical CPU is treated as a core of its own on node 0.
+    fn read_topology(num_cpus: usize) -> Vec<Core> {
+        let online = fs::read_to_string(format!("{}/online", CPU_DIR)).ok()
+            .and_then(|list| cgroup::parse_list(&list))
+            .unwrap_or((0..num_cpus).collect());
+
+        let mut nodes = Vec::new();
+        if let Ok(entries) = fs::read_dir(NODE_DIR) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with("node") {
+                    continue;
+                }
+                if let (Ok(node), Some(cpus)) = (name["node".len()..].parse::<usize>(),
+                        fs::read_to_string(entry.path().join("cpulist")).ok()
+                            .and_then(|list| cgroup::parse_list(&list))) {
+                    nodes.push((node, cpus));
+                }
+            }
+        }
+        let node_of = |cpu: usize| {
+            nodes.iter().find(|(_, cpus)| cpus.contains(&cpu)).map(|(node, _)| *node).unwrap_or(0)
+        };
+
+        let mut cores: Vec<Core> = Vec::new();
+        for cpu in online {
+            if cores.iter().any(|c| c.cpus.contains(&cpu)) {
+                continue;
+            }
+            let siblings = fs::read_to_string(format!("{}/cpu{}/topology/thread_siblings_list", CPU_DIR, cpu))
+                .ok()
+                .and_then(|list| cgroup::parse_list(&list))
+                .unwrap_or(vec![cpu]);
+            cores.push(Core { cpus: siblings, node: node_of(cpu) });
+        }
+        cores
+    }
+
+    // Pick `req_cpus` CPUs out of `free`, all on the same NUMA node. Whole cores are preferred so
+    // that VMs with dedicated CPUs don't share hyperthread pairs. Returns the node and the CPUs.
+    fn pick_cpus(&self, req_cpus: usize, free: &BTreeSet<usize>) -> Option<(usize, Vec<usize>)> {
+        let nodes: BTreeSet<usize> = self.cores.iter().map(|c| c.node).collect();
+        for node in nodes {
+            let cores: Vec<&Core> = self.cores.iter().filter(|c| c.node == node).collect();
+            let mut picked = Vec::new();
+            for core in cores.iter().filter(|c| c.cpus.iter().all(|cpu| free.contains(cpu))) {
+                if picked.len() + core.cpus.len() <= req_cpus {
+                    picked.extend(core.cpus.iter().cloned());
+                }
+            }
+            for cpu in cores.iter().flat_map(|c| c.cpus.iter()) {
+                if picked.len() == req_cpus {
+                    break;
+                }
+                if free.contains(cpu) && !picked.contains(cpu) {
+                    picked.push(*cpu);
+                }
+            }
+            if picked.len() == req_cpus {
+                picked.sort();
+                return Some((node, picked));
+            }
+        }
+        None
+    }
+
+    pub fn can_allocate_cpus(&self, req_cpus: usize, freed: Option<&Cpuset>) -> bool {
+        if req_cpus == 0 {
+            return true;
+        }
+        let mut free = self.free_cpu_set.clone();
+        if let Some(freed) = freed {
+            free.extend(freed.cpus.iter().cloned());
+        }
+        self.pick_cpus(req_cpus, &free).is_some()
+    }
+
+    pub fn allocate_cpus(&mut self, req_cpus: usize) -> Option<Cpuset> {
+        let (node, cpus) = self.pick_cpus(req_cpus, &self.free_cpu_set)?;
+        for cpu in cpus.iter() {
+            self.free_cpu_set.remove(cpu);
+        }
+        self.free_cpu = self.free_cpu_set.len() as u64;
+        Some(Cpuset { cpus, mems: vec![node] })
+    }
+
+    pub fn free_cpus(&mut self, cpuset: &Cpuset) {
+        self.free_cpu_set.extend(cpuset.cpus.iter().cloned());
+        self.free_cpu = self.free_cpu_set.len() as u64;
+    }
 }
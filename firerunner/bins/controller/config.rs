@@ -3,7 +3,7 @@ extern crate serde_yaml;
 use serde::{Deserialize};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::fs::File;
+use std::io::Read;
 
 // represents an in-memory function config store
 #[derive(Clone)]
@@ -14,7 +14,7 @@ pub struct Configuration {
 }
 
 impl Configuration {
-    pub fn new<R: AsRef<Path>, A: AsRef<Path>>(runtimefs_dir: R, appfs_dir: A, config_file: File) -> Configuration {
+    pub fn new<R: AsRef<Path>, A: AsRef<Path>, C: Read>(runtimefs_dir: R, appfs_dir: A, config_file: C) -> Configuration {
         let mut config = Configuration {
             configs: BTreeMap::new(),
             runtimefs_dir: [runtimefs_dir].iter().collect(),
@@ -47,6 +47,10 @@ impl Configuration {
                 memory: c.memory,
                 concurrency_limit: c.concurrency_limit,
                 runtime: c.runtime.clone(), 
+                cpu_mode: c.cpu_mode,
+                scratch_mib: c.scratch_mib,
+                disk_bandwidth: c.disk_bandwidth,
+                disk_iops: c.disk_iops,
             }
         })
     }
@@ -78,5 +82,21 @@ pub struct FunctionConfig {
     pub memory: usize,
     pub concurrency_limit: usize,
     pub runtime: String,
+    #[serde(default)]
+    pub cpu_mode: Option<CpuMode>, // overrides the controller's --cpu_mode
+    #[serde(default)]
+    pub scratch_mib: Option<usize>,    // size of a writable scratch disk, none if unset
+    #[serde(default)]
+    pub disk_bandwidth: Option<u64>,   // in bytes/s, for each of the function's drives
+    #[serde(default)]
+    pub disk_iops: Option<u64>,
+}
+
+// Whether a function's VMs share all CPUs with other VMs or get CPUs of their own
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuMode {
+    Shared,
+    Dedicated,
 }
 
@@ -1,31 +1,86 @@
 use std::collections::btree_map::BTreeMap;
 use std::default::Default;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread::{self, JoinHandle};
 use std::fs::File;
+use std::io::Write;
 use std::path::PathBuf;
 use std::os::unix::io::FromRawFd;
+use std::time::{Duration, Instant};
 
-use super::config;
-use super::listener;
+use super::backend::{PendingVm, VmBackend, VmHandle};
+use super::clock::Clock;
+use super::config::{self, CpuMode};
+use super::listener::{self, Link};
 use super::request;
 use super::cluster;
-use super::metrics::Metrics;
+use super::metrics::{BootMode, Metrics};
+use super::snapshots::SnapshotCache;
+use super::vmm_metrics::{self, VmmMetricsCollector};
 
-use firerunner::runner::{VmApp, VmAppConfig};
-use firerunner::pipe_pair::PipePair;
+use firerunner::cgroup::{CgroupConfig, Cpuset};
+use firerunner::error::Error;
+use firerunner::jail::JailConfig;
+use firerunner::runner::{DriveLimits, LaunchMode, ScratchDisk, VmAppConfig, VmmLoggerConfig};
 
 const VM_SIZE_INCREMENT: usize = 128; // in MB
 const CPU_SHARE_INCREMENT: usize = 64;
+const CPU_SHARE_PER_VCPU: u64 = 1024;
+
+// How the vCPU count and CPU share of a VM are derived
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuPolicy {
+    Vcpus,        // the function's configured vcpus, with CPU_SHARE_PER_VCPU per vCPU
+    Proportional, // both proportional to the function's memory
+    Fixed { vcpu_count: u64, cpu_share: u64 }, // the same for every VM
+}
+
+// Where a scheduled request went
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    Warm(u32),   // sent to this idle VM
+    Booted(u32), // sent to this newly launched VM, which gets it once it is ready
+    Dropped,
+}
+
+// A VM for a booter thread to launch
+pub struct Boot {
+    vm: BootingVm,
+    config: VmAppConfig,
+    evict: Option<Box<VmHandle>>,
+}
+
+// What the scheduler needs to know about a VM once it is launched
+pub struct BootingVm {
+    id: u32,
+    function: String,
+    user_id: u32,
+    boot_mode: BootMode,
+}
+
+// Work for the scheduler thread, which owns `Inner`, metrics included. Everything that reads or
+// changes the controller's state goes through it, in the order it was sent.
+pub enum Event {
+    Schedule(request::Request, Option<Sender<Placement>>), // the sender gets where the request went
+    Response((u32, u32, String, Option<u64>, Vec<u8>)),    // from the RequestManager
+    Ready(u32, String, u32, u64),                          // VM id, function, user id, when it connected
+    RequestTimestamp(u32, u64),                            // VM id, when a request was sent or answered
+    Disconnected(u32, String, u32),                        // VM id, function, user id: its connection broke
+    Booted(BootingVm, u64, Result<Box<VmHandle>, Error>),  // from a booter thread, with when the launch started
+    Call(Box<FnMut(&mut Inner) + Send>),
+    Stop,
+}
+
 // represent an VM from a management perspective
 // differs from runner::VmApp or VmAppConfig that represent an Vm from execution perspective
 #[derive(Debug)]
 pub struct Vm {
     pub id: u32,
-    pub req_sender: Sender<request::Request>,
-    pub app: VmApp,
+    pub req_sender: listener::RequestSender,
+    pub app: Box<VmHandle>,
+    pub request_id: Option<u64>, // of the last request sent to it, if that has an id
 }
 
 pub struct Inner {
@@ -33,31 +88,108 @@ pub struct Inner {
     running_functions: BTreeMap<String, BTreeMap<u32, Vec<Vm>>>,
     idle_functions: BTreeMap<String, BTreeMap<u32, Vec<Vm>>>,
 
-    channels: Arc<Mutex<BTreeMap<u32, (String, u32, Receiver<request::Request>, PipePair)>>>,
+    // request channels and connections of VMs that aren't ready yet
+    channels: listener::Channels,
     vm_id_counter: AtomicUsize,     // monotonically increase for each vm created
 
     function_configs: config::Configuration,    // in-memory function config store
     seccomp_level: u32,
     cmd_line: String,
     kernel: String,
-    stat: Arc<Mutex<Metrics>>,
+    stat: Metrics,
     notifier: File,
-    debug: bool,          // whether VMs keeps stdout
-    snapshot: Option<PathBuf>,
+    waker: Arc<listener::Waker>, // tells the RequestManager about new requests
+    backend: Arc<VmBackend>,
+    booter: Option<Sender<Boot>>, // launches VMs off the scheduling path once ignited
+    boot_threads: usize,
+    boot_timeout: Option<Duration>, // after which a launched VM that isn't ready counts as a boot failure
+    booting: BTreeMap<u32, (Instant, String, u32)>, // VMs that aren't ready: deadline, function, user id
+    clock: Arc<Clock>,
+    snapshots: Option<SnapshotCache>,
     one_hyperthread_mem_size: usize,
+    cgroup_parent: PathBuf,
+    vmm_mem_overhead: usize, // in MB, added to the guest memory to get a VM's memory limit
+    cpu_mode: CpuMode,       // for functions that don't set their own
+    cpu_policy: CpuPolicy,
+    cpu_quota: Option<f64>,  // CPUs worth of time each vCPU may use
+    jail: Option<JailConfig>,
+    launch_mode: LaunchMode,
+    log_dir: Option<PathBuf>, // per-VM logs
+    vmm_metrics_interval: Option<Duration>, // how often VMMs flush metrics to the log dir
+    pause_idle: bool,     // whether idle VMs are paused until they get a request
+    scratch_dir: PathBuf,
+    completions: Option<Sender<(u64, Option<Vec<u8>>)>>, // responses to requests with an id, none if dropped
+    accepting: bool,      // false once shutdown started
+}
+
+// How the controller launches and places VMs. The defaults are the command line's.
+#[derive(Clone, Debug)]
+pub struct ControllerSettings {
+    pub seccomp_level: u32,
+    pub cmd_line: String,
+    pub kernel: String,
+    pub snapshot: Option<PathBuf>, // directory of snapshots to boot from
+    pub mem_size: usize,           // in MB, 0 for the machine's memory
+    pub cgroup_parent: PathBuf,
+    pub vmm_mem_overhead: usize,   // in MB, added to the guest memory to get a VM's memory limit
+    pub cpu_mode: CpuMode,         // for functions that don't set their own
+    pub cpu_policy: CpuPolicy,
+    pub cpu_quota: Option<f64>,    // CPUs worth of time each vCPU may use, None for no limit
+    pub jail: Option<JailConfig>,
+    pub launch_mode: LaunchMode,
+    pub log_dir: Option<PathBuf>,  // per-VM logs
+    pub vmm_metrics_interval: Option<Duration>, // how often VMMs flush metrics to the log dir
+    pub pause_idle: bool,          // whether idle VMs are paused until they get a request
+    pub scratch_dir: PathBuf,
+    pub boot_threads: usize,       // 0 launches VMs while scheduling
+    pub boot_timeout: Option<Duration>, // None waits forever
+}
+
+impl Default for ControllerSettings {
+    fn default() -> ControllerSettings {
+        ControllerSettings {
+            seccomp_level: 0,
+            cmd_line: String::new(),
+            kernel: String::new(),
+            snapshot: None,
+            mem_size: 0,
+            cgroup_parent: PathBuf::from(firerunner::cgroup::DEFAULT_PARENT),
+            vmm_mem_overhead: 64,
+            cpu_mode: CpuMode::Shared,
+            cpu_policy: CpuPolicy::Proportional,
+            cpu_quota: None,
+            jail: None,
+            launch_mode: LaunchMode::Fork,
+            log_dir: None,
+            vmm_metrics_interval: None,
+            pause_idle: false,
+            scratch_dir: PathBuf::from("/tmp"),
+            boot_threads: 4,
+            boot_timeout: Some(Duration::from_secs(30)),
+        }
+    }
 }
 
 pub struct Controller {
-    inner: Arc<Mutex<Inner>>,
+    events: Sender<Event>,
+    scheduler: Option<JoinHandle<()>>,
     listener: File,       // this is cloned and used by RequestManger
+    wakeups: File,        // read end of the waker's pipe, likewise
+    handle: Option<Handle>, // RequestManager, booter and VMM metrics collector threads
+    stopping: Arc<AtomicBool>,
 }
 
 impl Controller {
-    pub fn new(function_configs: config::Configuration, seccomp_level: u32,
-               cmd_line: String, kernel: String, debug: bool,
-               snapshot: Option<PathBuf>, mem_size: usize) -> Controller {
+    pub fn new(function_configs: config::Configuration, backend: Box<VmBackend>, clock: Box<Clock>,
+               settings: ControllerSettings) -> Controller {
+        let ControllerSettings {
+            seccomp_level, cmd_line, kernel, snapshot, mem_size, cgroup_parent, vmm_mem_overhead,
+            cpu_mode, cpu_policy, cpu_quota, jail, launch_mode, log_dir, vmm_metrics_interval, pause_idle,
+            scratch_dir, boot_threads, boot_timeout,
+        } = settings;
 
         let (listener, notifier) = nix::unistd::pipe().expect("Failed to create a pipe");
+        let (waker, wakeups) = listener::Waker::new();
 
         // initialize running and idle lists upfront
         let mut running_functions = BTreeMap::new();
@@ -79,182 +211,465 @@ impl Controller {
 
         let my_cluster = cluster::Cluster::new(mem_size);
 
+        let snapshots = snapshot.map(|dir| SnapshotCache::new(dir, &kernel, &function_configs));
+
         let mut one_hyperthread_mem_size: usize = 1024;
 
         if mem_size == 0 {
             one_hyperthread_mem_size = my_cluster.total_mem / my_cluster.total_cpu as usize;
         }
 
-        Controller {
-            inner: Arc::new(Mutex::new(Inner {
-                cluster: my_cluster,
-                running_functions,
-                idle_functions,
-
-                channels: Default::default(),
-                vm_id_counter: AtomicUsize::new(3),
-                seccomp_level,
-                cmd_line,
-                kernel,
-                function_configs,
-                stat: Arc::new(Mutex::new(Metrics::new())),
-                notifier: unsafe{ File::from_raw_fd(notifier) },
-                debug,
-                snapshot,
-                one_hyperthread_mem_size,
-            })),
+        let inner = Inner {
+            cluster: my_cluster,
+            running_functions,
+            idle_functions,
+
+            channels: Default::default(),
+            vm_id_counter: AtomicUsize::new(3),
+            seccomp_level,
+            cmd_line,
+            kernel,
+            function_configs,
+            stat: Metrics::new(),
+            notifier: unsafe{ File::from_raw_fd(notifier) },
+            waker: Arc::new(waker),
+            backend: Arc::from(backend),
+            booter: None,
+            boot_threads,
+            boot_timeout,
+            booting: BTreeMap::new(),
+            clock: Arc::from(clock),
+            snapshots,
+            one_hyperthread_mem_size,
+            cgroup_parent,
+            vmm_mem_overhead,
+            cpu_mode,
+            cpu_policy,
+            cpu_quota,
+            jail,
+            launch_mode,
+            log_dir,
+            vmm_metrics_interval,
+            pause_idle,
+            scratch_dir,
+            completions: None,
+            accepting: true,
+        };
+        let (events, event_receiver) = channel();
+        let scheduler = thread::spawn(move || inner.run(event_receiver));
 
+        Controller {
+            events,
+            scheduler: Some(scheduler),
             listener: unsafe{ File::from_raw_fd(listener) },
+            wakeups,
+            handle: None,
+            stopping: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn schedule(&mut self, req: request::Request) {
-        self.inner.lock().unwrap().aws_schedule(req);
+    // Where the scheduler placed `req`
+    pub fn schedule(&mut self, req: request::Request) -> Placement {
+        let (sender, receiver) = channel();
+        self.events.send(Event::Schedule(req, Some(sender))).expect("Scheduler thread exited");
+        receiver.recv().expect("Scheduler thread exited")
+    }
+
+    // Hand `req` to the scheduler without waiting for it to be placed
+    pub fn dispatch(&mut self, req: request::Request) {
+        self.events.send(Event::Schedule(req, None)).expect("Scheduler thread exited");
     }
 
-    pub fn ignite(&mut self) -> Handle {
-        let (response_sender, response_receiver) = channel();
+    // Complete the request VM `id` is running without a response from the VM, for VMs that
+    // only exist in a simulation
+    pub fn complete(&mut self, id: u32, user_id: u32, function: String) {
+        call(&self.events, move |inner| inner.complete(id, user_id, function))
+    }
+
+    // Update the metrics, in order with everything else sent to the scheduler
+    pub fn update_stat<F: FnMut(&mut Metrics) + Send + 'static>(&self, mut update: F) {
+        self.events.send(Event::Call(Box::new(move |inner| update(&mut inner.stat))))
+            .expect("Scheduler thread exited");
+    }
 
-        // Create RequestManager thread
+    // Responses to requests that have an id, from now on. Requests that are dropped after they
+    // were placed, e.g. because their VM failed to boot or crashed, get none.
+    pub fn completions(&mut self) -> Receiver<(u64, Option<Vec<u8>>)> {
+        call(&self.events, |inner| {
+            let (sender, receiver) = channel();
+            inner.completions = Some(sender);
+            receiver
+        })
+    }
+
+    pub fn ignite(&mut self) {
+        // Create RequestManager thread, which sends responses to the scheduler
         let listener = self.listener.try_clone().expect("Failed to clone pipe listener");
-        let (chans, stats) = {
-            let inner = self.inner.lock().unwrap();
-            (inner.channels.clone(), inner.stat.clone())
-        };
+        let wakeups = self.wakeups.try_clone().expect("Failed to clone waker pipe");
+        let (chans, waker, boot_threads, backend, clock, log_dir, interval) = call(&self.events, |inner| {
+            (inner.channels.clone(), inner.waker.clone(), inner.boot_threads, inner.backend.clone(),
+             inner.clock.clone(), inner.log_dir.clone(), inner.vmm_metrics_interval)
+        });
 
         let manager_handle = listener::RequestManager::new(chans,
-                                                           stats,
-                                                           response_sender, listener)
+                                                           self.events.clone(),
+                                                           listener,
+                                                           waker,
+                                                           wakeups)
                                                       .spawn();
 
-        // Create ResponseHandler thread
-        let inner = self.inner.clone();
-        let response_handle = thread::spawn(move || {
-            for response in response_receiver.iter() {
-                inner.lock().unwrap().process_response(response);
+        let mut handles = vec![manager_handle];
+
+        // Create booter threads, which exit once the scheduler drops the booter sender
+        if boot_threads > 0 {
+            let (boot_sender, boot_receiver) = channel();
+            let boot_receiver = Arc::new(Mutex::new(boot_receiver));
+            for _ in 0..boot_threads {
+                let (events, backend, clock) = (self.events.clone(), backend.clone(), clock.clone());
+                let boot_receiver = boot_receiver.clone();
+                handles.push(thread::spawn(move || {
+                    loop {
+                        let boot = match boot_receiver.lock().unwrap().recv() {
+                            Ok(boot) => boot,
+                            Err(_) => break,
+                        };
+                        boot_vm(&events, &*backend, &*clock, boot);
+                    }
+                }));
             }
-        });
+            call(&self.events, move |inner| inner.booter = Some(boot_sender));
+        }
+
+        // Create VMM metrics collector thread, which collects once more when stopping
+        if let (Some(log_dir), Some(interval)) = (log_dir, interval) {
+            let stopping = self.stopping.clone();
+            let events = self.events.clone();
+            handles.push(thread::spawn(move || {
+                let mut collector = VmmMetricsCollector::new(log_dir);
+                loop {
+                    let stop = stopping.load(Ordering::SeqCst);
+                    let mut samples = collector.collect();
+                    if !samples.is_empty() {
+                        let _ = events.send(Event::Call(Box::new(move |inner| {
+                            for (id, sample) in samples.drain(..) {
+                                inner.stat.log_vmm_metrics(id, &sample);
+                            }
+                        })));
+                    }
+                    if stop {
+                        break;
+                    }
+                    thread::sleep(interval);
+                }
+            }));
+        }
 
-        Handle (vec![manager_handle, response_handle])
+        self.handle = Some(Handle (handles));
     }
 
     // check if there's any running function
     pub fn check_running(&self) -> u64{
-        let mut num_running: u64 = 0;
-        for (_, run_tree) in self.inner.lock().unwrap().running_functions.iter() {
-            for (_, run_list) in run_tree.iter() {
-                num_running = num_running + run_list.len() as u64;
-            }
-        }
-        return num_running;
+        call(&self.events, |inner| inner.num_running())
     }
 
-    // kill all vms
-    pub fn kill_all(&mut self) {
-        for idle_tree in self.inner.lock().unwrap().idle_functions.values_mut() {
-            for vms in idle_tree.values_mut(){
-                vms.clear()
+    // Stop accepting requests, give in-flight requests up to `drain_timeout` to complete, then
+    // kill all VMs, wait for them to exit and stop the RequestManager, booters and collector.
+    // The scheduler keeps running until the controller is dropped, to answer for its metrics.
+    pub fn shutdown(&mut self, drain_timeout: Duration) {
+        call(&self.events, |inner| {
+            inner.accepting = false;
+            // booter threads finish their current boot, the VM of which is then discarded
+            inner.booter = None;
+        });
+
+        let deadline = Instant::now() + drain_timeout;
+        while self.check_running() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let mut vms = call(&self.events, |inner| inner.take_all_vms());
+        for vm in vms.iter_mut() {
+            if let Err(e) = vm.app.kill() {
+                println!("Failed to kill VM {}: {}", vm.id, e);
+            }
+        }
+        for vm in vms.iter_mut() {
+            if let Err(e) = vm.app.wait() {
+                println!("Failed to wait for VM {}: {}", vm.id, e);
             }
         }
+        // dropping the VMs removes their cgroups and closes their request channels,
+        // which lets the RequestManager drop their connections
+        drop(vms);
 
-        for run_tree in self.inner.lock().unwrap().running_functions.values_mut() {
-            for vms in run_tree.values_mut() {
-                vms.clear()
+        call(&self.events, |inner| {
+            inner.notifier.write_all(&listener::SHUTDOWN_SIGNAL.to_le_bytes())
+                .expect("Failed to signal RequestManager")
+        });
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                println!("Controller thread panicked");
             }
         }
     }
 
     pub fn get_cluster_info(&self) -> cluster::Cluster {
-        self.inner.lock().unwrap().cluster.clone()
+        call(&self.events, |inner| inner.cluster.clone())
     }
 
     pub fn get_stat(&self) -> Metrics {
-        self.inner.lock().unwrap().stat.lock().unwrap().clone()
+        call(&self.events, |inner| inner.stat.clone())
     }
 }
 
+impl Drop for Controller {
+    fn drop(&mut self) {
+        let _ = self.events.send(Event::Stop);
+        if let Some(scheduler) = self.scheduler.take() {
+            if scheduler.join().is_err() {
+                println!("Scheduler thread panicked");
+            }
+        }
+    }
+}
+
+// Run `f` on the scheduler thread and wait for its result
+fn call<T, F>(events: &Sender<Event>, f: F) -> T
+    where T: Send + 'static, F: FnOnce(&mut Inner) -> T + Send + 'static
+{
+    let (sender, receiver) = channel();
+    let mut f = Some(f);
+    events.send(Event::Call(Box::new(move |inner| {
+        if let Some(f) = f.take() {
+            let _ = sender.send(f(inner));
+        }
+    }))).expect("Scheduler thread exited");
+    receiver.recv().expect("Scheduler thread exited")
+}
+
 impl Inner {
 
+    // The scheduler's event loop
+    fn run(mut self, events: Receiver<Event>) {
+        loop {
+            // wake up in time to give up on VMs that take too long to boot
+            let event = match self.booting.values().map(|&(deadline, _, _)| deadline).min() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                    match events.recv_timeout(timeout) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.expire_boots();
+                            continue;
+                        },
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                },
+                None => match events.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
+            match event {
+                Event::Schedule(req, placed) => {
+                    let t1 = time::precise_time_ns();
+                    let placement = self.schedule(req);
+                    self.stat.log_schedule_latency(time::precise_time_ns() - t1);
+                    if let Some(placed) = placed {
+                        let _ = placed.send(placement);
+                    }
+                },
+                Event::Response(response) => self.process_response(response),
+                Event::Ready(id, function, user_id, timestamp) => self.ready(id, function, user_id, timestamp),
+                Event::RequestTimestamp(id, timestamp) => self.stat.log_request_timestamp(id, timestamp),
+                Event::Disconnected(id, function, user_id) => self.disconnected(id, function, user_id),
+                Event::Booted(vm, started, launched) => self.booted(vm, started, launched),
+                Event::Call(mut f) => f(&mut self),
+                Event::Stop => break,
+            }
+        }
+    }
+
+    fn schedule(&mut self, req: request::Request) -> Placement {
+        if !self.accepting {
+            self.stat.drop_req(1);
+            return Placement::Dropped;
+        }
+        self.aws_schedule(req)
+    }
+
+    fn complete(&mut self, id: u32, user_id: u32, function: String) {
+        let mut request_id = None;
+        if let Some(channel) = self.channels.lock().expect("poisoned lock").get(&id) {
+            while let Ok(req) = channel.2.try_recv() {
+                request_id = req.id;
+            }
+        }
+        self.process_response((id, user_id, function, request_id, Vec::new()));
+    }
+
+    fn num_running(&self) -> u64 {
+        let mut num_running: u64 = 0;
+        for (_, run_tree) in self.running_functions.iter() {
+            for (_, run_list) in run_tree.iter() {
+                num_running = num_running + run_list.len() as u64;
+            }
+        }
+        return num_running;
+    }
+
     // Send a request to the vm. If success, push the vm to the running_function vector.
     // If not, push the vm the idle_function vector.
-    fn send_request(&mut self, req: request::Request, vm: Vm) {
+    fn send_request(&mut self, req: request::Request, mut vm: Vm) {
         let function_name = req.function.clone();
-        let request_sender = vm.req_sender.clone();
         let user_id = req.user_id;
-        let vm_id = vm.id;
-
-        self.running_functions.get_mut(&function_name).unwrap().get_mut(&user_id).unwrap().push(vm);
+        vm.request_id = req.id;
 
-        if let Err(e) = request_sender.send(req) {
-            println!("Request failed to send to vm: {}, error: {}", vm_id, e);
+        match vm.req_sender.send(req) {
+            Ok(()) => {
+                self.running_functions.get_mut(&function_name).unwrap().get_mut(&user_id).unwrap().push(vm);
+            },
+            Err(e) => {
+                println!("Request failed to send to vm: {}, error: {}", vm.id, e);
+                self.push_idle(&function_name, user_id, vm);
+            },
+        }
+    }
 
-            let vm = self.find_and_remove_running_vm(&function_name, &user_id, vm_id).unwrap();
-            let idle_list = self.idle_functions.get_mut(&function_name).unwrap().get_mut(&user_id).unwrap();
-            idle_list.push(vm);
+    // Put a VM on the idle list, pausing it first if idle VMs are paused. A VM that fails to
+    // pause is left running.
+    fn push_idle(&mut self, function: &String, user_id: u32, mut vm: Vm) {
+        if self.pause_idle {
+            if let Err(e) = vm.app.pause() {
+                println!("Failed to pause vm: {}, error: {}", vm.id, e);
+            }
         }
+        self.idle_functions.get_mut(function).unwrap().get_mut(&user_id).unwrap().push(vm);
+    }
 
+    // Tear down a VM that is neither running nor idle anymore and release its resources
+    fn discard_vm(&mut self, function: &String, vm: Vm) {
+        self.channels.lock().expect("poisoned lock").remove(&vm.id);
+        self.booting.remove(&vm.id);
+        let (_, mem) = self.function_configs.resource_req(function).unwrap();
+        self.cluster.free(0, mem);
+        if let Some(cpuset) = vm.app.cpuset() {
+            self.cluster.free_cpus(0, cpuset);
+        }
     }
 
 
-    fn aws_schedule(&mut self, req: request::Request) {
+    fn aws_schedule(&mut self, req: request::Request) -> Placement {
         // Check if I have an idle VM
         match self.get_idle_vm(&req) {
             Some(vm) => {
 //                println!("Found idle VM for {}", req.function);
-//                self.stat.lock().unwrap().log_request_timestamp(vm.id, time::precise_time_ns());
+//                self.stat.log_request_timestamp(vm.id, time::precise_time_ns());
+                let id = vm.id;
                 self.send_request(req, vm);
+                Placement::Warm(id)
             },
             None => {
                 if self.check_concurrency(&req) {
 //                    println!("Dropping request for {}", &req.function);
-                    self.stat.lock().unwrap().drop_req_concurrency(1);
-                    self.stat.lock().unwrap().drop_req(1);
-                    return;
+                    self.stat.drop_req_concurrency(1);
+                    self.stat.drop_req(1);
+                    return Placement::Dropped;
                 }
 
                 // Check if there's enough free resource to launch a new VM
                 let (_, req_mem) = self.function_configs.resource_req(&req.function).unwrap();
+                let req_cpus = self.dedicated_cpus(&req.function);
 
-                match self.cluster.find_free_machine(req_mem) {
+                match self.cluster.find_free_machine(req_mem, req_cpus) {
                     Some((host_id,_)) => {
                         self.cluster.allocate(host_id, req_mem);
-
-                        let new_vm = self.launch_new_vm(&req, None);
-//                        println!("New VM: {:?}", new_vm);
-//                        self.stat.lock().unwrap()
-//                            .log_request_timestamp(new_vm.id, time::precise_time_ns());
-                        self.send_request(req, new_vm);
+                        let cpuset = if req_cpus > 0 {
+                            self.cluster.allocate_cpus(host_id, req_cpus)
+                        } else {
+                            None
+                        };
+
+                        match self.launch_new_vm(&req, None, cpuset.clone()) {
+                            Ok(new_vm) => {
+//                                println!("New VM: {:?}", new_vm);
+//                                self.stat.lock().unwrap()
+//                                    .log_request_timestamp(new_vm.id, time::precise_time_ns());
+                                let id = new_vm.id;
+                                self.send_request(req, new_vm);
+                                Placement::Booted(id)
+                            },
+                            Err(e) => {
+                                self.cluster.free(host_id, req_mem);
+                                if let Some(ref cpuset) = cpuset {
+                                    self.cluster.free_cpus(host_id, cpuset);
+                                }
+                                self.boot_failed(&req.function, e);
+                                Placement::Dropped
+                            }
+                        }
                     },
                     // Evict an idle VM running some other functions
                     None => {
 //                        println!("No free resources, picking a VM to evict");
-                        if let Some((evict_vm, evict_mem)) = self.get_evictable_vm(&req) {
-
-                            self.stat.lock().unwrap().evict_vm(1);
-                            let new_vm = self.evict_and_swap(&req, evict_vm);
+                        if let Some((evict_vm, evict_mem)) = self.get_evictable_vm(&req, req_cpus) {
+
+                            self.stat.evict_vm(1);
+                            // get_evictable_vm made sure the evicted VM frees up enough CPUs
+                            if let Some(cpuset) = evict_vm.app.cpuset() {
+                                self.cluster.free_cpus(0, cpuset);
+                            }
+                            let cpuset = if req_cpus > 0 {
+                                self.cluster.allocate_cpus(0, req_cpus)
+                            } else {
+                                None
+                            };
+                            let new_vm = self.evict_and_swap(&req, evict_vm, cpuset.clone());
 
                             self.cluster.free(0, evict_mem);
-                            let (_, req_mem) = self.function_configs
-                                                         .resource_req(&req.function)
-                                                         .unwrap();
-                            self.cluster.allocate(0, req_mem);
-
-//                            println!("new vm {:?}", &new_vm);
-//                            self.stat.lock().unwrap()
-//                                .log_request_timestamp(new_vm.id, time::precise_time_ns());
-                            self.send_request(req, new_vm);
+                            match new_vm {
+                                Ok(new_vm) => {
+                                    let (_, req_mem) = self.function_configs
+                                                                 .resource_req(&req.function)
+                                                                 .unwrap();
+                                    self.cluster.allocate(0, req_mem);
+
+//                                    println!("new vm {:?}", &new_vm);
+//                                    self.stat.lock().unwrap()
+//                                        .log_request_timestamp(new_vm.id, time::precise_time_ns());
+                                    let id = new_vm.id;
+                                    self.send_request(req, new_vm);
+                                    Placement::Booted(id)
+                                },
+                                Err(e) => {
+                                    if let Some(ref cpuset) = cpuset {
+                                        self.cluster.free_cpus(0, cpuset);
+                                    }
+                                    self.boot_failed(&req.function, e);
+                                    Placement::Dropped
+                                }
+                            }
                        } else {
                             //println!("Dropping request for {}", &req.function);
-                            self.stat.lock().unwrap().drop_req_resource(1);
-                            self.stat.lock().unwrap().drop_req(1);
+                            self.stat.drop_req_resource(1);
+                            self.stat.drop_req(1);
+                            Placement::Dropped
                         }
-                        return;
                     }
                 }
             }
         }
     }
 
+    // A VM could not be launched for `function`, so its request is dropped
+    fn boot_failed(&mut self, function: &String, e: Error) {
+        println!("Failed to launch VM for {}: {}", function, e);
+        self.stat.boot_fail(1);
+        self.stat.drop_req(1);
+    }
+
 //    fn omni_schedule(&self, req: request::Request) {
 //
 //    }
@@ -273,20 +688,51 @@ impl Inner {
 
         // For a particular function, acquire an idle VM instance
     pub fn get_idle_vm(&mut self, req: &request::Request) -> Option<Vm> {
-        if let Some(idle_tree) = self.idle_functions.get_mut(&req.function){
-            if let Some(vms) = idle_tree.get_mut(&req.user_id) {
-                return vms.pop();
+        loop {
+            let vm = match self.idle_functions.get_mut(&req.function)
+                               .and_then(|idle_tree| idle_tree.get_mut(&req.user_id)) {
+                Some(vms) => vms.pop(),
+                None => None,
+            };
+            let mut vm = match vm {
+                Some(vm) => vm,
+                None => return None,
+            };
+            if !self.pause_idle {
+                return Some(vm);
+            }
+
+            let t0 = self.clock.now_ns();
+            match vm.app.resume() {
+                Ok(()) => {
+                    let t1 = self.clock.now_ns();
+                    self.stat.log_resume_latency(vm.id, t1 - t0);
+                    return Some(vm);
+                },
+                // the VM is unusable, try the next one
+                Err(e) => {
+                    println!("Failed to resume vm: {}, error: {}", vm.id, e);
+                    self.discard_vm(&req.function, vm);
+                }
             }
         }
-        None
     }
 
-    pub fn get_evictable_vm(&mut self, req: &request::Request) -> Option<(Vm, usize)> {
+    // Pick an idle VM to make room for `req`. Evicting it has to free up enough memory and,
+    // together with the currently free CPUs, `req_cpus` dedicated CPUs.
+    pub fn get_evictable_vm(&mut self, req: &request::Request, req_cpus: usize) -> Option<(Vm, usize)> {
         let req_mem: usize = self.function_configs.get(&req.function).unwrap().memory;
         let user_id: u32 = req.user_id;
+        let cluster = &self.cluster;
 
         for (func_name, idle_tree) in self.idle_functions.iter_mut() {
             for (id, idle_list) in idle_tree.iter_mut(){
+                let frees_cpus = idle_list.last().map(|vm| {
+                    cluster.can_allocate_cpus(0, req_cpus, vm.app.cpuset())
+                }).unwrap_or(false);
+                if !frees_cpus {
+                    continue;
+                }
                 if func_name != &req.function {
                     let evict_mem: usize = self.function_configs.get(&func_name).unwrap().memory;
 
@@ -306,82 +752,299 @@ impl Inner {
 
     }
 
-    pub fn evict_and_swap(&self, req: &request::Request, evict_vm: Vm) -> Vm {
-        let t0 = time::precise_time_ns();
+    pub fn evict_and_swap(&mut self, req: &request::Request, evict_vm: Vm, cpuset: Option<Cpuset>) -> Result<Vm, Error> {
+        let t0 = self.clock.now_ns();
         let id = evict_vm.id;
-        let t1 = time::precise_time_ns();
+        let t1 = self.clock.now_ns();
         {
-            let mut stat = self.stat.lock().unwrap();
-            stat.log_eviction_timestamp(id, t0);
-            stat.log_eviction_timestamp(id, t1);
+            self.stat.log_eviction_timestamp(id, t0);
+            self.stat.log_eviction_timestamp(id, t1);
         }
-        self.launch_new_vm(req, Some(evict_vm))
+        self.launch_new_vm(req, Some(evict_vm), cpuset)
     }
 
-    fn cpu_share(&self, mem: usize) -> u64 {
-        ((mem / VM_SIZE_INCREMENT) * CPU_SHARE_INCREMENT) as u64
+    // vCPU count and CPU share of a VM of `config` under the controller's CPU policy
+    fn cpu_resources(&self, config: &config::FunctionConfig) -> (u64, u64) {
+        match self.cpu_policy {
+            CpuPolicy::Vcpus => (config.vcpus, config.vcpus * CPU_SHARE_PER_VCPU),
+            CpuPolicy::Proportional => {
+                let mem = config.memory;
+                let vcpu_count = (mem as f64 / self.one_hyperthread_mem_size as f64).ceil() as u64;
+                (vcpu_count, ((mem / VM_SIZE_INCREMENT) * CPU_SHARE_INCREMENT) as u64)
+            },
+            CpuPolicy::Fixed { vcpu_count, cpu_share } => (vcpu_count, cpu_share),
+        }
     }
 
-    fn vcpu_count(&self, mem: usize) -> u64 {
-        let count = (mem as f64 / self.one_hyperthread_mem_size as f64).ceil();
-        count as u64
+    // Number of CPUs to dedicate to a VM of `function`, 0 if it runs on shared CPUs
+    fn dedicated_cpus(&self, function: &String) -> usize {
+        let config = self.function_configs.get(function).unwrap();
+        match config.cpu_mode.unwrap_or(self.cpu_mode) {
+            CpuMode::Shared => 0,
+            CpuMode::Dedicated => self.cpu_resources(&config).0 as usize,
+        }
     }
 
-    pub fn launch_new_vm(&self, req: &request::Request, evict_vm: Option<Vm>) -> Vm {
+    // `cpuset` is the set of dedicated CPUs allocated for the VM, if any
+    pub fn launch_new_vm(&mut self, req: &request::Request, evict_vm: Option<Vm>, cpuset: Option<Cpuset>) -> Result<Vm, Error> {
         let config = self.function_configs.get(&req.function).unwrap();
 
         let id = self.vm_id_counter.fetch_add(1, Ordering::Relaxed) as u32;
-        let (req_sender, req_receiver) = channel();
-
-        let load_dir = self.snapshot.as_ref().map(|snapshots| {
-            let mut dir = PathBuf::new();
-            dir.push(snapshots);
-            dir.push(format!("{}-{}", config.runtime, config.memory));
-            dir
-        });
+        let (req_sender, req_receiver) = listener::request_channel(id, &self.waker);
 
         let mem = config.memory;
-        let cpu_share = self.cpu_share(mem);
-        let vcpu_count = self.vcpu_count(mem);
+        let (vcpu_count, cpu_share) = self.cpu_resources(&config);
+
+        // fall back to a cold boot if there is no valid snapshot for this VM
+        let (load_dir, boot_mode) = match self.snapshots.as_mut().and_then(|snapshots| {
+            snapshots.find(&config, mem, vcpu_count)
+        }) {
+            Some((dir, true)) => (Some(dir.clone()), BootMode::FunctionSnapshot),
+            Some((dir, false)) => (Some(dir.clone()), BootMode::Snapshot),
+            None => (None, BootMode::Cold),
+        };
 
         {
-            self.stat.lock().unwrap().log_boot_timestamp(id, time::precise_time_ns());
-            self.stat.lock().unwrap().log_vm_mem_size(id, mem);
+            let stat = &mut self.stat;
+            stat.log_vm_function(id, &config.name);
+            stat.log_vm_mem_size(id, mem);
+            stat.log_vm_cpu(id, vcpu_count, cpu_share, cpuset.as_ref().map(|c| &c.cpus));
         }
 
-        let app = VmAppConfig {
+        let app_config = VmAppConfig {
             kernel: self.kernel.clone(),
             //kernel: String::from("foo"),
             instance_id: config.name.clone(),
-            rootfs: config.runtimefs,
-            appfs: Some(config.appfs),
+            rootfs: config.runtimefs.clone(),
+            appfs: Some(config.appfs.clone()),
             cmd_line: self.cmd_line.clone(),
             seccomp_level: self.seccomp_level,
             vsock_cid: id,
             notifier: self.notifier.try_clone().expect("Failed to clone notifier"),
-            // we really want this to be a function of VPU and memory count, so that
-            // cpu_share is proportional to the size of the function
-            cpu_share: cpu_share,
+            cgroup: CgroupConfig {
+                parent: self.cgroup_parent.clone(),
+                cpu_share: cpu_share,
+                cpu_quota: self.cpu_quota.map(|quota| quota * vcpu_count as f64),
+                mem_limit_mib: Some(mem + self.vmm_mem_overhead),
+                cpuset,
+            },
+            jail: self.jail.clone(),
+            launch_mode: self.launch_mode.clone(),
+            log_file: self.log_dir.as_ref().map(|dir| dir.join(format!("{}-{}.log", id, config.name))),
+            vmm_logger: match (&self.log_dir, self.vmm_metrics_interval) {
+                (Some(dir), Some(interval)) => Some(VmmLoggerConfig {
+                    log_file: dir.join(format!("{}-{}.vmm.log", id, config.name)),
+                    metrics_file: dir.join(format!("{}-{}.{}", id, config.name, vmm_metrics::EXTENSION)),
+                    flush_interval_ms: interval.as_millis() as u64,
+                }),
+                _ => None,
+            },
+            scratch: config.scratch_mib.map(|size_mib| ScratchDisk {
+                path: self.scratch_dir.join(format!("{}-{}.scratch", id, config.name)),
+                size_mib,
+            }),
+            drive_limits: match (config.disk_bandwidth, config.disk_iops) {
+                (None, None) => None,
+                (bandwidth, ops) => Some(DriveLimits { bandwidth, ops }),
+            },
             vcpu_count: vcpu_count,
             mem_size_mib: Some(config.memory),
             load_dir,
-            dump_dir: None, // ignored by now
-        }.run(self.debug, evict_vm.map(|e| e.app));
+            dump_dir: None,
+        };
+        let boot = Boot {
+            vm: BootingVm {
+                id,
+                function: req.function.clone(),
+                user_id: req.user_id,
+                boot_mode,
+            },
+            config: app_config,
+            evict: evict_vm.map(|e| e.app),
+        };
 
+        // the boot timeout starts now, so that it covers waiting for and running the launch
+        let deadline = self.boot_timeout.map(|timeout| (Instant::now() + timeout, req.function.clone(), req.user_id));
+        // the VM may be ready before its launch returns
         self.channels.lock()
             .expect("poisoned lock")
-            .insert(id,
-                    (req.function.clone(),
-                     req.user_id,
-                     req_receiver,
-                     app.connection.try_clone().expect("Failed to clone VmApp's pipe pair"))
-            );
-
-        Vm {
+            .insert(id, (req.function.clone(), req.user_id, req_receiver, Link::Launching));
+
+        // without booter threads, e.g. in a simulation, launch right away
+        let app: Box<VmHandle> = match self.booter {
+            Some(ref booter) => {
+                let pending = PendingVm { cpuset: boot.config.cgroup.cpuset.clone() };
+                booter.send(boot).expect("Booter threads exited");
+                if let Some(deadline) = deadline {
+                    self.booting.insert(id, deadline);
+                }
+                Box::new(pending)
+            },
+            None => {
+                let started = self.clock.now_ns();
+                let app = match self.backend.launch(boot.config, boot.evict) {
+                    Ok(app) => app,
+                    Err(e) => {
+                        self.channels.lock().expect("poisoned lock").remove(&id);
+                        return Err(e);
+                    },
+                };
+                self.log_boot(&boot.vm, started);
+                self.attach_connection(id, &*app);
+                // simulated VMs never connect
+                if let (Some(deadline), Some(_)) = (deadline, app.connection()) {
+                    self.booting.insert(id, deadline);
+                }
+                app
+            },
+        };
+
+        Ok(Vm {
             id,
             req_sender,
             app,
+            request_id: None,
+        })
+    }
+
+    // Let the RequestManager serve the launched VM `id` once it is ready, right away if it
+    // already signalled that
+    fn attach_connection(&mut self, id: u32, app: &VmHandle) {
+        let connection = match app.connection() {
+            Some(connection) => connection.try_clone().expect("Failed to clone VmApp's pipe pair"),
+            None => return,
+        };
+        let notified = match self.channels.lock().expect("poisoned lock").get_mut(&id) {
+            Some(channel) => match std::mem::replace(&mut channel.3, Link::Connected(connection)) {
+                Link::Notified => true,
+                _ => false,
+            },
+            None => false,
+        };
+        if notified {
+            self.notifier.write_all(&id.to_le_bytes()).expect("Failed to notify the RequestManager");
+        }
+    }
+
+    // Record how a VM that launched successfully at `started` boots. It is ready once it
+    // connects, which logs its second boot timestamp.
+    fn log_boot(&mut self, vm: &BootingVm, started: u64) {
+        self.stat.log_boot_timestamp(vm.id, started);
+        if self.snapshots.is_some() {
+            self.stat.snapshot_lookup(vm.boot_mode != BootMode::Cold);
+        }
+        self.stat.log_boot_mode(vm.id, vm.boot_mode);
+    }
+
+    // A booter thread is done launching `vm`, which it started at `started`. Until then the VM
+    // was a `PendingVm` on the running list, with the request that caused its boot.
+    fn booted(&mut self, vm: BootingVm, started: u64, launched: Result<Box<VmHandle>, Error>) {
+        let (id, function, user_id) = (vm.id, vm.function.clone(), vm.user_id);
+        match launched {
+            Ok(mut app) => {
+                // the VM is gone if the controller shut down in the meantime
+                if self.find_running_vm(&function, &user_id, id).is_none() {
+                    let _ = app.kill().and_then(|_| app.wait());
+                    return;
+                }
+                if app.connection().is_none() {
+                    self.booting.remove(&id);
+                }
+                self.log_boot(&vm, started);
+                self.attach_connection(id, &*app);
+                self.find_running_vm(&function, &user_id, id).unwrap().app = app;
+            },
+            Err(e) => {
+                if let Some(vm) = self.find_and_remove_running_vm(&function, &user_id, id) {
+                    self.report_dropped(&vm);
+                    self.discard_vm(&function, vm);
+                    self.boot_failed(&function, e);
+                }
+            },
+        }
+    }
+
+    // Tell whoever waits for the request `vm` was running that it was dropped
+    fn report_dropped(&self, vm: &Vm) {
+        if let (Some(request_id), Some(completions)) = (vm.request_id, self.completions.as_ref()) {
+            let _ = completions.send((request_id, None));
+        }
+    }
+
+    // VM `id` connected at `timestamp`. It was booted for a request, so it is still running
+    // unless the controller let go of it in the meantime.
+    fn ready(&mut self, id: u32, function: String, user_id: u32, timestamp: u64) {
+        if self.find_running_vm(&function, &user_id, id).is_some() {
+            self.booting.remove(&id);
+            self.stat.log_boot_timestamp(id, timestamp);
+        }
+    }
+
+    // Give up on VMs that didn't become ready within the boot timeout of being dispatched, along
+    // with the requests they were booted for. A VM still launching on a booter thread is killed
+    // once its launch returns.
+    fn expire_boots(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self.booting.iter()
+            .filter(|&(_, &(deadline, _, _))| deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let (_, function, user_id) = self.booting.remove(&id).unwrap();
+            println!("VM {} for {} did not become ready in time", id, function);
+            self.stat.boot_fail(1);
+            match self.find_and_remove_running_vm(&function, &user_id, id) {
+                Some(mut vm) => {
+                    self.stat.drop_req(1);
+                    self.report_dropped(&vm);
+                    let _ = vm.app.kill().and_then(|_| vm.app.wait());
+                    self.discard_vm(&function, vm);
+                },
+                None => {
+                    self.channels.lock().expect("poisoned lock").remove(&id);
+                },
+            }
+        }
+    }
+
+    // VM `id`'s connection broke, e.g. because it crashed. The request it was running, if any,
+    // is lost. VMs the controller already let go of are ignored.
+    fn disconnected(&mut self, id: u32, function: String, user_id: u32) {
+        let vm = match self.find_and_remove_running_vm(&function, &user_id, id) {
+            Some(vm) => {
+                self.stat.drop_req(1);
+                self.report_dropped(&vm);
+                Some(vm)
+            },
+            None => {
+                let idle_list = self.idle_functions.get_mut(&function).unwrap().get_mut(&user_id).unwrap();
+                idle_list.iter().position(|vm| vm.id == id).map(|idx| idle_list.remove(idx))
+            },
+        };
+        if let Some(mut vm) = vm {
+            println!("Lost connection to VM {} for {}", id, function);
+            self.stat.vm_crash(1);
+            let _ = vm.app.kill().and_then(|_| vm.app.wait());
+            self.discard_vm(&function, vm);
+        }
+    }
+
+    fn find_running_vm(&mut self, function_name: &String, user_id: &u32, vm_id: u32) -> Option<&mut Vm> {
+        self.running_functions.get_mut(function_name).unwrap().get_mut(user_id).unwrap()
+            .iter_mut().find(|vm| vm.id == vm_id)
+    }
+
+    // Remove all VMs, running or idle, from the controller
+    fn take_all_vms(&mut self) -> Vec<Vm> {
+        let mut vms = Vec::new();
+        for tree in self.idle_functions.values_mut().chain(self.running_functions.values_mut()) {
+            for list in tree.values_mut() {
+                vms.extend(list.drain(..));
+            }
         }
+        self.channels.lock().expect("poisoned lock").clear();
+        self.booting.clear();
+        vms
     }
 
     fn find_and_remove_running_vm(&mut self, function_name: &String, user_id: &u32, vm_id: u32) -> Option<Vm> {
@@ -398,20 +1061,27 @@ impl Inner {
         return None;
     }
 
-    pub fn process_response(&mut self, response: (u32, u32, String, Vec<u8>)) {
-        let (id, user_id, function, _response_data) = response;
-//        self.stat.lock().unwrap().log_request_timestamp(id, time::precise_time_ns());
+    pub fn process_response(&mut self, response: (u32, u32, String, Option<u64>, Vec<u8>)) {
+        let (id, user_id, function, request_id, response_data) = response;
+//        self.stat.log_request_timestamp(id, time::precise_time_ns());
         //println!("{}, {}, {}: {}", id, user_id, function, String::from_utf8(response).unwrap());
 
-        self.stat.lock().unwrap().complete_req(1);
-
+        // the request was already dropped if the controller let go of the VM, e.g. because it
+        // timed out booting, or is shutting down
+        let vm = match self.find_and_remove_running_vm(&function, &user_id, id) {
+            Some(vm) => vm,
+            None => {
+                self.stat.stale_response(1);
+                return;
+            },
+        };
 
-        if let Some(vm) = self.find_and_remove_running_vm(&function, &user_id, id) {
-            let idle_list = self.idle_functions.get_mut(&function).unwrap().get_mut(&user_id).unwrap();
-            idle_list.push(vm);
-        } else {
-            panic!("results returned from a non-running VM (id: {})", id);
+        self.stat.complete_req(1);
+        if let (Some(request_id), Some(completions)) = (request_id, self.completions.as_ref()) {
+            // the receiver is gone once the closed loop stopped waiting
+            let _ = completions.send((request_id, Some(response_data)));
         }
+        self.push_idle(&function, user_id, vm);
 
 //        {
 //            let mut running_tree = self.running_functions.lock().unwrap();
@@ -425,10 +1095,22 @@ impl Inner {
     }
 }
 
+// Launch a VM on a booter thread, so that the scheduler doesn't wait for it
+fn boot_vm(events: &Sender<Event>, backend: &VmBackend, clock: &Clock, boot: Boot) {
+    let Boot { vm, config, evict } = boot;
+    // no need to launch the VM if the controller shut down in the meantime
+    let (id, function, user_id) = (vm.id, vm.function.clone(), vm.user_id);
+    if !call(events, move |inner| inner.find_running_vm(&function, &user_id, id).is_some()) {
+        return;
+    }
+    let started = clock.now_ns();
+    let launched = backend.launch(config, evict);
+    let _ = events.send(Event::Booted(vm, started, launched));
+}
+
 pub struct Handle(Vec<JoinHandle<()>>);
 
 impl Handle {
-    #[allow(dead_code)]
     pub fn join(mut self) -> std::thread::Result<()> {
         let mut result = Ok(());
         while let Some(handle) = self.0.pop() {
@@ -440,3 +1122,182 @@ impl Handle {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use super::super::backend::FakeBackend;
+    use super::super::clock::WallClock;
+
+    const FUNCTIONS: &str = "
+- {name: a, runtimefs: none, appfs: none, runtime: none, users: 2, vcpus: 1, memory: 128, concurrency_limit: 2}
+- {name: b, runtimefs: none, appfs: none, runtime: none, users: 1, vcpus: 1, memory: 128, concurrency_limit: 2}
+";
+
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // An ignited controller with `mem_size` MB for fake VMs, and its completions
+    fn controller(backend: FakeBackend, mem_size: usize, boot_timeout: Duration)
+                  -> (Controller, Receiver<(u64, Option<Vec<u8>>)>) {
+        let settings = ControllerSettings {
+            mem_size,
+            cpu_policy: CpuPolicy::Fixed { vcpu_count: 1, cpu_share: CPU_SHARE_PER_VCPU },
+            boot_timeout: Some(boot_timeout),
+            ..Default::default()
+        };
+        let configs = config::Configuration::new("", "", FUNCTIONS.as_bytes());
+        let mut controller = Controller::new(configs, Box::new(backend), Box::new(WallClock), settings);
+        let completions = controller.completions();
+        controller.ignite();
+        (controller, completions)
+    }
+
+    fn fake(fail_every: Option<u64>) -> FakeBackend {
+        FakeBackend {
+            boot_delay: Duration::from_millis(10),
+            latency: Duration::from_millis(5),
+            fail_every,
+        }
+    }
+
+    fn request(function: &str, user_id: u32, id: u64) -> request::Request {
+        request::Request {
+            timestamp: 0,
+            function: function.to_string(),
+            payload: json!({"request": id}),
+            user_id,
+            id: Some(id),
+        }
+    }
+
+    // Schedule a request and wait for its response, none if it was dropped after it was placed
+    fn run(controller: &mut Controller, completions: &Receiver<(u64, Option<Vec<u8>>)>,
+           req: request::Request) -> (Placement, Option<Vec<u8>>) {
+        let id = req.id.unwrap();
+        let placement = controller.schedule(req);
+        if placement == Placement::Dropped {
+            return (placement, None);
+        }
+        let (completed, response) = completions.recv_timeout(RESPONSE_TIMEOUT).expect("no response");
+        assert_eq!(completed, id);
+        (placement, response)
+    }
+
+    // The VM a request was booted on
+    fn booted(placement: Placement) -> u32 {
+        match placement {
+            Placement::Booted(vm) => vm,
+            placement => panic!("request placed as {:?} instead of booting a VM", placement),
+        }
+    }
+
+    #[test]
+    fn reuses_idle_vms() {
+        let (mut controller, completions) = controller(fake(None), 1024, RESPONSE_TIMEOUT);
+
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 0));
+        let vm = booted(placement);
+        assert!(response.is_some());
+        assert_eq!(run(&mut controller, &completions, request("a", 0, 1)).0, Placement::Warm(vm));
+        // VMs aren't shared between users
+        assert_ne!(booted(run(&mut controller, &completions, request("a", 1, 2)).0), vm);
+
+        controller.shutdown(Duration::from_secs(1));
+        let stat = controller.get_stat();
+        assert_eq!(stat.num_complete, 3);
+        assert_eq!(stat.num_drop, 0);
+        assert_eq!(stat.boot_timestamp.len(), 2);
+        // both VMs became ready
+        assert!(stat.boot_timestamp.values().all(|timestamps| timestamps.len() == 2));
+    }
+
+    #[test]
+    fn evicts_idle_vms_under_memory_pressure() {
+        // only one VM fits
+        let (mut controller, completions) = controller(fake(None), 128, RESPONSE_TIMEOUT);
+
+        // the only VM is busy, so there is nothing to evict
+        booted(controller.schedule(request("a", 0, 0)));
+        assert_eq!(controller.schedule(request("b", 0, 1)), Placement::Dropped);
+        assert_eq!(completions.recv_timeout(RESPONSE_TIMEOUT).unwrap().0, 0);
+
+        // once it is idle, it makes room for another function's VM and the other way round
+        let (placement, response) = run(&mut controller, &completions, request("b", 0, 2));
+        booted(placement);
+        assert!(response.is_some());
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 3));
+        booted(placement);
+        assert!(response.is_some());
+
+        controller.shutdown(Duration::from_secs(1));
+        let stat = controller.get_stat();
+        assert_eq!(stat.num_complete, 3);
+        assert_eq!(stat.num_drop, 1);
+        assert_eq!(stat.num_drop_resource, 1);
+        assert_eq!(stat.num_evict, 2);
+        assert_eq!(stat.boot_timestamp.len(), 3);
+    }
+
+    #[test]
+    fn drops_requests_of_failing_vms() {
+        let (mut controller, completions) = controller(fake(Some(2)), 1024, RESPONSE_TIMEOUT);
+
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 0));
+        let vm = booted(placement);
+        assert!(response.is_some());
+        // the VM fails the second request and is torn down
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 1));
+        assert_eq!(placement, Placement::Warm(vm));
+        assert!(response.is_none());
+        // so the next request boots a new VM
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 2));
+        assert_ne!(booted(placement), vm);
+        assert!(response.is_some());
+
+        controller.shutdown(Duration::from_secs(1));
+        let stat = controller.get_stat();
+        assert_eq!(stat.num_complete, 2);
+        assert_eq!(stat.num_drop, 1);
+        assert_eq!(stat.num_vm_crash, 1);
+        assert_eq!(stat.num_boot_fail, 0);
+    }
+
+    #[test]
+    fn serves_vms_that_are_ready_before_their_launch_returns() {
+        // the fake VMs signal readiness as soon as they start, as a snapshot restore might
+        let instant = FakeBackend { boot_delay: Duration::from_millis(0), ..fake(None) };
+        let (mut controller, completions) = controller(instant, 1024, Duration::from_secs(2));
+
+        for (id, &(function, user_id)) in [("a", 0), ("a", 1), ("b", 0)].iter().enumerate() {
+            let (placement, response) = run(&mut controller, &completions, request(function, user_id, id as u64));
+            booted(placement);
+            assert!(response.is_some());
+        }
+
+        controller.shutdown(Duration::from_secs(1));
+        let stat = controller.get_stat();
+        assert_eq!(stat.num_complete, 3);
+        assert_eq!(stat.num_drop, 0);
+        assert_eq!(stat.num_boot_fail, 0);
+    }
+
+    #[test]
+    fn drops_requests_of_vms_that_boot_too_slowly() {
+        let slow = FakeBackend { boot_delay: Duration::from_secs(5), ..fake(None) };
+        let (mut controller, completions) = controller(slow, 1024, Duration::from_millis(50));
+
+        let (placement, response) = run(&mut controller, &completions, request("a", 0, 0));
+        booted(placement);
+        assert!(response.is_none());
+
+        controller.shutdown(Duration::from_secs(1));
+        let stat = controller.get_stat();
+        assert_eq!(stat.num_complete, 0);
+        assert_eq!(stat.num_drop, 1);
+        assert_eq!(stat.num_boot_fail, 1);
+        // the VM never became ready
+        assert!(stat.boot_timestamp.values().all(|timestamps| timestamps.len() < 2));
+    }
+}
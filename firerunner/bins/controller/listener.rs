@@ -1,117 +1,399 @@
+// Connections to ready VMs. A single RequestManager thread serves all of them from an epoll
+// loop over non-blocking pipes: it learns which VMs are ready from the notifier pipe and which
+// VMs have new requests, or are gone, from the waker pipe `RequestSender`s write to.
 use std::collections::BTreeMap;
-use std::io::{Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
 use std::thread::{self, JoinHandle};
-//use firerunner::vsock::{self, VsockCloser, VsockListener};
 use std::fs::File;
 
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::epoll::{epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp};
+use nix::unistd;
+
+use super::controller::Event;
 use super::request;
 use firerunner::pipe_pair::PipePair;
-use super::metrics::Metrics;
 use time::precise_time_ns;
 
+// Written to the notifier pipe instead of a VM id to stop the RequestManager.
+// 0 is a reserved vsock CID, so it is never a VM id.
+pub const SHUTDOWN_SIGNAL: u32 = 0;
+
+// epoll tokens. VMs' response pipes use their id, their request pipes their id + WRITABLE.
+const NOTIFIER: u64 = std::u64::MAX;
+const WAKER: u64 = std::u64::MAX - 1;
+const WRITABLE: u64 = 1 << 32;
+
+const MAX_EVENTS: usize = 64;
+
+// The VMs the RequestManager doesn't serve yet, from when their launch is dispatched: function,
+// user id, request channel and how far the launch got
+pub type Channels = Arc<Mutex<BTreeMap<u32, (String, u32, Receiver<request::Request>, Link)>>>;
+
+// A VM may signal it is ready before its launch returns, e.g. when it restores a snapshot, so
+// the RequestManager keeps that in mind until the controller attaches the VM's connection.
+pub enum Link {
+    Launching,           // simulated VMs stay here, they never connect
+    Notified,            // still launching, but ready
+    Connected(PipePair), // launched, served once ready
+}
+
+fn set_nonblocking(fd: RawFd) -> nix::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)).map(drop)
+}
+
+// Tells the RequestManager which VMs to look at. If the pipe is full, the RequestManager looks
+// at every VM the next time it empties the pipe instead.
+#[derive(Debug)]
+pub struct Waker {
+    pipe: File,
+    overflowed: AtomicBool,
+}
+
+impl Waker {
+    // The waker and the read end of its pipe, for the RequestManager
+    pub fn new() -> (Waker, File) {
+        let (reader, writer) = unistd::pipe().expect("Failed to create a pipe");
+        set_nonblocking(writer).expect("Failed to make the waker pipe non-blocking");
+        let waker = Waker {
+            pipe: unsafe { File::from_raw_fd(writer) },
+            overflowed: AtomicBool::new(false),
+        };
+        (waker, unsafe { File::from_raw_fd(reader) })
+    }
+
+    fn wake(&self, id: u32) {
+        // writes of up to PIPE_BUF bytes happen entirely or not at all
+        if (&self.pipe).write_all(&id.to_le_bytes()).is_err() {
+            self.overflowed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// The sending half of a VM's request channel. Sending a request, and dropping the last
+// sender, wakes the RequestManager up to serve the VM.
+#[derive(Debug)]
+pub struct RequestSender {
+    id: u32,
+    sender: Option<Sender<request::Request>>, // only none while dropping
+    waker: Arc<Waker>,
+}
+
+pub fn request_channel(id: u32, waker: &Arc<Waker>) -> (RequestSender, Receiver<request::Request>) {
+    let (sender, receiver) = channel();
+    (RequestSender { id, sender: Some(sender), waker: waker.clone() }, receiver)
+}
+
+impl RequestSender {
+    pub fn send(&self, req: request::Request) -> Result<(), SendError<request::Request>> {
+        self.sender.as_ref().unwrap().send(req)?;
+        self.waker.wake(self.id);
+        Ok(())
+    }
+}
+
+impl Drop for RequestSender {
+    fn drop(&mut self) {
+        // the channel has to be closed before the RequestManager looks at it
+        self.sender.take();
+        self.waker.wake(self.id);
+    }
+}
+
 pub struct RequestManager {
     listener: File, // read end of the pipe through which VM signals it is ready to receive requests.
-    channels: Arc<Mutex<BTreeMap<u32, (String, u32, Receiver<request::Request>, PipePair)>>>,
-    stat: Arc<Mutex<Metrics>>,
-    connections: BTreeMap<u32, JoinHandle<()>>,
-    response_sender: Sender<(u32, u32, String, Vec<u8>)>,
+    wakeups: File,  // read end of the waker's pipe
+    waker: Arc<Waker>,
+    channels: Channels,
+    connections: BTreeMap<u32, Connection>,
+    events: Sender<Event>, // to the controller's scheduler
+    epoll: RawFd,
 }
 
 impl RequestManager {
-    pub fn new(channels: Arc<Mutex<BTreeMap<u32, (String, u32, Receiver<request::Request>, PipePair)>>>,
-                stat: Arc<Mutex<Metrics>>,
-                response_sender: Sender<(u32, u32, String, Vec<u8>)>,
-                listener: File) -> RequestManager
+    pub fn new(channels: Channels,
+                events: Sender<Event>,
+                listener: File,
+                waker: Arc<Waker>,
+                wakeups: File) -> RequestManager
     {
         RequestManager {
             listener: listener,
+            wakeups,
+            waker,
             channels,
-            stat,
             connections: BTreeMap::new(),
-            response_sender,
+            events,
+            epoll: -1,
         }
     }
 
     pub fn serve(&mut self) {
         println!("RequestManager Started");
 
-        let mut id_buf = [0u8; 4]; // u32
-        loop {
-//            println!("waiting for connection");
-            self.listener.read_exact(&mut id_buf).expect("Failed to read from listener pipe");
-            let id = u32::from_le_bytes(id_buf);
-            self.stat.lock().unwrap().log_boot_timestamp(id, time::precise_time_ns());
-
-//            println!("Connection from VM {}", &id);
-
-            if let Some((function, user_id, request_receiver, connection)) =
-                    self.channels.lock().expect("poisoned lock").remove(&id) {
-
-                let response_sender = self.response_sender.clone();
-                let stat = self.stat.clone();
-                self.connections.insert(id, thread::spawn(move || {
-                    let mut conn_mgr = ConnectionManager {
-                        id,
-                        user_id,
-                        function,
-                        request_receiver,
-                        response_sender,
-                        connection,
-                        stat,
-                    };
-                    conn_mgr.handle_connection();
-                }));
+        self.epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).expect("Failed to create epoll instance");
+        for &(fd, token) in [(self.listener.as_raw_fd(), NOTIFIER), (self.wakeups.as_raw_fd(), WAKER)].iter() {
+            set_nonblocking(fd).expect("Failed to make listener pipe non-blocking");
+            self.register(EpollOp::EpollCtlAdd, fd, EpollFlags::EPOLLIN, token).expect("Failed to watch listener pipe");
+        }
+
+        let mut ready = Vec::new();  // incomplete ids read from the pipes
+        let mut woken = Vec::new();
+        let mut events = vec![EpollEvent::empty(); MAX_EVENTS];
+        'serve: loop {
+            let n = match epoll_wait(self.epoll, &mut events, -1) {
+                Ok(n) => n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => panic!("Failed to wait for VM connections: {}", e),
+            };
+            for event in events[..n].iter() {
+                match event.data() {
+                    NOTIFIER => {
+                        let (ids, closed) = read_ids(&mut self.listener, &mut ready);
+                        for id in ids {
+                            if id == SHUTDOWN_SIGNAL {
+                                break 'serve;
+                            }
+                            self.connect(id);
+                        }
+                        if closed {
+                            break 'serve;
+                        }
+                    },
+                    WAKER => {
+                        let (ids, _) = read_ids(&mut self.wakeups, &mut woken);
+                        let ids = if self.waker.overflowed.swap(false, Ordering::SeqCst) {
+                            self.connections.keys().cloned().collect()
+                        } else {
+                            ids
+                        };
+                        for id in ids {
+                            self.serve_connection(id, false);
+                        }
+                    },
+                    token => {
+                        let readable = token & WRITABLE == 0 && event.events().intersects(
+                            EpollFlags::EPOLLIN | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR);
+                        self.serve_connection(token as u32, readable);
+                    },
+                }
             }
         }
+
+        // dropping the connections closes their pipes
+        self.connections.clear();
+        let _ = unistd::close(self.epoll);
+        println!("RequestManager Stopped");
     }
 
     pub fn spawn(mut self) -> JoinHandle<()> {
         thread::spawn(move || { self.serve() })
     }
-}
-
-struct ConnectionManager<T>{
-    id:  u32,
-    user_id: u32,
-    function: String,
-    request_receiver: Receiver<request::Request>,
-    response_sender: Sender<(u32, u32, String, Vec<u8>)>,
-    connection: T,
-    stat: Arc<Mutex<Metrics>>,
-}
 
-impl<T: Read + Write> ConnectionManager<T> {
+    fn register(&self, op: EpollOp, fd: RawFd, flags: EpollFlags, token: u64) -> nix::Result<()> {
+        epoll_ctl(self.epoll, op, fd, &mut EpollEvent::new(flags, token))
+    }
 
-    fn handle_request(connection: &mut T, request: request::Request) -> std::io::Result<Vec<u8>> {
+    // VM `id` is ready, start serving its requests. A VM that can't be served is torn down.
+    fn connect(&mut self, id: u32) {
+        let timestamp = precise_time_ns();
+        let channel = {
+            let mut channels = self.channels.lock().expect("poisoned lock");
+            match channels.get_mut(&id) {
+                Some(&mut (_, _, _, Link::Connected(_))) => channels.remove(&id),
+                // the controller notifies again once it attaches the connection
+                Some(channel) => {
+                    channel.3 = Link::Notified;
+                    None
+                },
+                None => None,
+            }
+        };
+        if let Some((function, user_id, request_receiver, Link::Connected(connection))) = channel {
+            let fds = (connection.response_reader.as_raw_fd(), connection.requests_input.as_raw_fd());
+            let watched = set_nonblocking(fds.0)
+                .and_then(|_| set_nonblocking(fds.1))
+                .and_then(|_| self.register(EpollOp::EpollCtlAdd, fds.0, EpollFlags::EPOLLIN, id as u64))
+                .and_then(|_| self.register(EpollOp::EpollCtlAdd, fds.1, EpollFlags::empty(), id as u64 + WRITABLE));
+            if let Err(e) = watched {
+                println!("Failed to watch connection of VM {}: {}", id, e);
+                let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, fds.0, None);
+                let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, fds.1, None);
+                let _ = self.events.send(Event::Disconnected(id, function, user_id));
+                return;
+            }
+            let _ = self.events.send(Event::Ready(id, function.clone(), user_id, timestamp));
+            self.connections.insert(id, Connection {
+                id,
+                user_id,
+                function,
+                request_receiver,
+                connection,
+                state: State::Idle,
+                writing: false,
+            });
+            // the request the VM was booted for is already waiting
+            self.serve_connection(id, false);
+        }
+    }
 
-        let mut request = serde_json::to_vec(&request).unwrap();
-        request.push(0xa); // newline
-        connection.write_all(request.as_slice())?;
-        let mut lens = [0; 4];
-        connection.read_exact(&mut lens)?;
-        let len = u32::from_be_bytes(lens);
-        if len == 0 {
-            return Ok(vec![]);
+    // Make as much progress on VM `id`'s requests as its pipes allow
+    fn serve_connection(&mut self, id: u32, readable: bool) {
+        let progress = match self.connections.get_mut(&id) {
+            Some(connection) => connection.advance(&self.events, readable),
+            None => return,
+        };
+        match progress {
+            Ok(writing) => {
+                let connection = &self.connections[&id];
+                if writing != connection.writing {
+                    let flags = if writing { EpollFlags::EPOLLOUT } else { EpollFlags::empty() };
+                    let fd = connection.connection.requests_input.as_raw_fd();
+                    if let Err(e) = self.register(EpollOp::EpollCtlMod, fd, flags, id as u64 + WRITABLE) {
+                        println!("Failed to watch connection of VM {}: {}", id, e);
+                    }
+                    self.connections.get_mut(&id).unwrap().writing = writing;
+                }
+            },
+            Err(e) => {
+                // the VM's pipes may have other descriptors, so they have to be unwatched
+                let connection = self.connections.remove(&id).unwrap();
+                let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, connection.connection.response_reader.as_raw_fd(), None);
+                let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, connection.connection.requests_input.as_raw_fd(), None);
+                // unless the controller let go of the VM, it has to be torn down
+                if e.kind() != ErrorKind::NotConnected {
+                    if e.kind() != ErrorKind::UnexpectedEof {
+                        println!("Error response from VM {}: {}", id, e);
+                    }
+                    let _ = self.events.send(Event::Disconnected(id, connection.function, connection.user_id));
+                }
+            },
         }
-        let mut response = Vec::with_capacity(len as usize);
-        response.resize(len as usize, 0);
-        connection.read_exact(response.as_mut_slice())?;
-        Ok(response)
     }
+}
 
-    fn handle_connection(&mut self) {
-        for request in self.request_receiver.iter() {
-            self.stat.lock().unwrap().log_request_timestamp(self.id,precise_time_ns());
-            if let Ok(response) = Self::handle_request(&mut self.connection, request) {
-                self.response_sender.send((self.id, self.user_id, self.function.clone(), response)).unwrap();
-                self.stat.lock().unwrap().log_request_timestamp(self.id,precise_time_ns());
-            } else {
-                println!("Error response from VM");
+// Read the ids written to `pipe` so far, and whether all its writers are gone. `partial` keeps
+// an incomplete id until the next call.
+fn read_ids(pipe: &mut File, partial: &mut Vec<u8>) -> (Vec<u32>, bool) {
+    let mut buf = [0u8; 4096];
+    let mut closed = false;
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) => {
+                closed = true;
                 break;
-            }
+            },
+            Ok(n) => partial.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => panic!("Failed to read from listener pipe: {}", e),
+        }
+    }
+    let complete = partial.len() - partial.len() % 4;
+    let ids = partial[..complete].chunks(4)
+        .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+        .collect();
+    partial.drain(..complete);
+    (ids, closed)
+}
+
+enum State {
+    Idle,
+    Writing { request_id: Option<u64>, buf: Vec<u8>, written: usize },
+    // the response's 4 byte length, then the response
+    Reading { request_id: Option<u64>, buf: Vec<u8>, read: usize, body: bool },
+}
+
+struct Connection {
+    id: u32,
+    user_id: u32,
+    function: String,
+    request_receiver: Receiver<request::Request>,
+    connection: PipePair,
+    state: State,
+    writing: bool, // whether the request pipe is watched for being writable
+}
+
+impl Connection {
+    // Send requests and read responses until the pipes would block or there are no more
+    // requests. Returns whether a request is waiting for the request pipe to become writable.
+    // Fails with `NotConnected` once the VM is gone from the controller and with
+    // `UnexpectedEof` once the VM closed its end.
+    fn advance(&mut self, events: &Sender<Event>, readable: bool) -> io::Result<bool> {
+        loop {
+            let next = match self.state {
+                State::Idle => {
+                    // the event may be stale if the response was read on a wakeup since
+                    if readable {
+                        match self.connection.read(&mut [0u8; 1]) {
+                            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                            Ok(_) => return Err(io::Error::new(ErrorKind::InvalidData, "unsolicited response")),
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => (),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    match self.request_receiver.try_recv() {
+                        Ok(request) => {
+                            let _ = events.send(Event::RequestTimestamp(self.id, precise_time_ns()));
+                            let request_id = request.id;
+                            let mut buf = serde_json::to_vec(&request).unwrap();
+                            buf.push(0xa); // newline
+                            State::Writing { request_id, buf, written: 0 }
+                        },
+                        Err(TryRecvError::Empty) => return Ok(false),
+                        Err(TryRecvError::Disconnected) => {
+                            return Err(io::Error::new(ErrorKind::NotConnected, "VM is gone"));
+                        },
+                    }
+                },
+                State::Writing { request_id, ref buf, ref mut written } => {
+                    match self.connection.write(&buf[*written..]) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(n) => *written += n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                    if *written < buf.len() {
+                        continue;
+                    }
+                    State::Reading { request_id, buf: vec![0; 4], read: 0, body: false }
+                },
+                State::Reading { request_id, ref mut buf, ref mut read, ref mut body } => {
+                    match self.connection.read(&mut buf[*read..]) {
+                        Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                        Ok(n) => *read += n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                    if *read < buf.len() {
+                        continue;
+                    }
+                    if !*body {
+                        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+                        *buf = vec![0; len];
+                        *read = 0;
+                        *body = true;
+                        if len > 0 {
+                            continue;
+                        }
+                    }
+                    let response = std::mem::replace(buf, Vec::new());
+                    // the response timestamp is logged first, as the response may make the VM idle.
+                    // The scheduler only exits once the controller is dropped.
+                    let _ = events.send(Event::RequestTimestamp(self.id, precise_time_ns()));
+                    let _ = events.send(Event::Response((self.id, self.user_id, self.function.clone(), request_id, response)));
+                    State::Idle
+                },
+            };
+            self.state = next;
         }
-//        println!("Connection Manager exit");
     }
 }
@@ -1,3 +1,5 @@
+#![recursion_limit = "256"]
+
 #[macro_use(crate_version, crate_authors)]
 extern crate clap;
 extern crate firerunner;
@@ -5,32 +7,161 @@ extern crate serde;
 extern crate serde_json;
 extern crate vmm;
 extern crate nix;
-extern crate cgroups;
 extern crate time;
 extern crate indicatif;
+extern crate rand;
+extern crate flate2;
 
-use std::io::BufRead;
 use serde_json::json;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use std::fs::File;
 use std::error::Error;
-use std::io::{Write, Seek, SeekFrom};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use indicatif::ProgressBar;
+use backend::{FakeBackend, FirecrackerBackend, SimBackend, VmBackend};
+use clock::{Clock, VirtualClock, WallClock};
+use closed_loop::ClosedLoop;
+use simulation::{Profile, Simulation};
+use firerunner::jail::JailConfig;
+use firerunner::runner::LaunchMode;
+use indicatif::{ProgressBar, ProgressStyle};
+use nix::sys::signal::{SigSet, Signal};
 
+mod azure;
+mod backend;
+mod clock;
+mod closed_loop;
 mod config;
 mod controller;
 mod request;
 mod listener;
 mod cluster;
 mod metrics;
+mod snapshots;
+mod simulation;
+mod vmm_metrics;
+mod workload;
 
 fn main() {
     let cmd_arguments = App::new("controller")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Controller for serverless runtime based on Firecracker")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("gen-workload")
+                .about("Generate a request trace from a workload config (see workloads/generator.py)")
+                .arg(
+                    Arg::with_name("workload config")
+                        .value_name("WORKLOAD_CONFIG")
+                        .required(true)
+                        .help("YAML workload config")
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Where to write the requests, stdout by default")
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0")
+                        .help("Seed of the random arrivals")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-azure")
+                .about("Convert an Azure Functions 2019 trace into requests")
+                .arg(
+                    Arg::with_name("invocations")
+                        .long("invocations")
+                        .value_name("CSV")
+                        .takes_value(true)
+                        .required(true)
+                        .help("invocations_per_function_md.anon.dNN.csv")
+                )
+                .arg(
+                    Arg::with_name("durations")
+                        .long("durations")
+                        .value_name("CSV")
+                        .takes_value(true)
+                        .required(false)
+                        .help("function_durations_percentiles.anon.dNN.csv, adds each function's average duration to its payloads")
+                )
+                .arg(
+                    Arg::with_name("memory")
+                        .long("memory")
+                        .value_name("CSV")
+                        .takes_value(true)
+                        .required(false)
+                        .help("app_memory_percentiles.anon.dNN.csv, needed to map functions by memory")
+                )
+                .arg(
+                    Arg::with_name("function config file")
+                        .long("fconfig")
+                        .value_name("FUNCTION_CONFIG")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Functions to map the trace's functions onto")
+                )
+                .arg(
+                    Arg::with_name("mapping")
+                        .long("mapping")
+                        .value_name("MAPPING")
+                        .takes_value(true)
+                        .required(false)
+                        .possible_values(&["memory", "round-robin"])
+                        .default_value("round-robin")
+                        .help("Map trace functions onto the smallest configured functions with enough memory, or round-robin")
+                )
+                .arg(
+                    Arg::with_name("time scale")
+                        .long("time_scale")
+                        .value_name("FACTOR")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("1")
+                        .help("Multiply timestamps by FACTOR, e.g. 0.1 replays the trace 10 times as fast")
+                )
+                .arg(
+                    Arg::with_name("minutes")
+                        .long("minutes")
+                        .value_name("MINUTES")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Only import the first MINUTES minutes")
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Where to write the requests, stdout by default")
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0")
+                        .help("Seed of the invocation times within each minute")
+                )
+        )
         .arg(
             Arg::with_name("kernel")
                 .short("k")
@@ -56,8 +187,8 @@ fn main() {
                 .long("requests")
                 .value_name("REQUEST_FILE")
                 .takes_value(true)
-                .required(true)
-                .help("File containing JSON-lines with requests")
+                .required_unless("closed loop")
+                .help("File containing JSON-lines with requests, - for stdin. Files ending in .gz are decompressed")
         )
         .arg(
             Arg::with_name("function config file")
@@ -89,7 +220,7 @@ fn main() {
                 .long("debug")
                 .takes_value(false)
                 .required(false)
-                .help("Whether VMs get to write to stdout")
+                .help("Whether VMs get to write to stdout (unless --log_dir is set)")
         )
         .arg(
             Arg::with_name("snapshot dir")
@@ -116,27 +247,387 @@ fn main() {
                 .required(false)
                 .help("JSON file to store output metrics (outputs to STDOUT by default)")
         )
+        .arg(
+            Arg::with_name("cgroup parent")
+                .long("cgroup_parent")
+                .value_name("CGROUP_PARENT")
+                .takes_value(true)
+                .required(false)
+                .default_value(firerunner::cgroup::DEFAULT_PARENT)
+                .help("Parent cgroup, relative to the cgroup root, of the VMs' cgroups")
+        )
+        .arg(
+            Arg::with_name("vmm memory overhead")
+                .long("vmm_mem_overhead")
+                .value_name("MB")
+                .takes_value(true)
+                .required(false)
+                .default_value("64")
+                .help("Memory allowed on top of the guest memory before a VM is OOM killed")
+        )
+        .arg(
+            Arg::with_name("cpu mode")
+                .long("cpu_mode")
+                .value_name("MODE")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["shared", "dedicated"])
+                .default_value("shared")
+                .help("Whether VMs share all CPUs or get pinned to CPUs of their own, unless set per function")
+        )
+        .arg(
+            Arg::with_name("cpu policy")
+                .long("cpu_policy")
+                .value_name("POLICY")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["vcpus", "proportional", "fixed"])
+                .default_value("proportional")
+                .help("Size VMs' vCPU count and CPU share by the functions' vcpus, proportionally to their memory, or with --vcpus and --cpu_share")
+        )
+        .arg(
+            Arg::with_name("vcpus")
+                .long("vcpus")
+                .value_name("VCPUS")
+                .takes_value(true)
+                .required(false)
+                .default_value("1")
+                .help("vCPU count of every VM with --cpu_policy fixed")
+        )
+        .arg(
+            Arg::with_name("cpu share")
+                .long("cpu_share")
+                .value_name("SHARE")
+                .takes_value(true)
+                .required(false)
+                .default_value("1024")
+                .help("CPU share of every VM with --cpu_policy fixed")
+        )
+        .arg(
+            Arg::with_name("cpu quota")
+                .long("cpu_quota")
+                .value_name("CPUS")
+                .takes_value(true)
+                .required(false)
+                .help("Cap each vCPU of a VM at CPUS CPUs worth of time, e.g. 0.5, instead of letting VMs use idle CPUs")
+        )
+        .arg(
+            Arg::with_name("seccomp level")
+                .long("seccomp_level")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["0", "1", "2"])
+                .default_value("0")
+                .help("Seccomp filtering of VMM threads: 0 none, 1 by syscall number, 2 by syscall number and arguments")
+        )
+        .arg(
+            Arg::with_name("jail")
+                .long("jail")
+                .takes_value(false)
+                .required(false)
+                .requires_all(&["jail uid", "jail gid"])
+                .help("Run each VM chrooted, in its own namespaces and as an unprivileged user")
+        )
+        .arg(
+            Arg::with_name("chroot base")
+                .long("chroot_base")
+                .value_name("DIR")
+                .takes_value(true)
+                .required(false)
+                .default_value("/srv/firerunner")
+                .help("Directory under which jailed VMs get their chroot")
+        )
+        .arg(
+            Arg::with_name("jail uid")
+                .long("uid")
+                .value_name("UID")
+                .takes_value(true)
+                .required(false)
+                .help("User jailed VMs run as")
+        )
+        .arg(
+            Arg::with_name("jail gid")
+                .long("gid")
+                .value_name("GID")
+                .takes_value(true)
+                .required(false)
+                .help("Group jailed VMs run as")
+        )
+        .arg(
+            Arg::with_name("launch mode")
+                .long("launch")
+                .value_name("MODE")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["fork", "exec"])
+                .default_value("fork")
+                .help("Run VMs in forks of the controller or in firerunner-vmm processes")
+        )
+        .arg(
+            Arg::with_name("vmm binary")
+                .long("vmm_binary")
+                .value_name("PATH")
+                .takes_value(true)
+                .required(false)
+                .help("firerunner-vmm binary for --launch exec (defaults to the one next to the controller)")
+        )
+        .arg(
+            Arg::with_name("log dir")
+                .long("log_dir")
+                .value_name("LOG_DIR")
+                .takes_value(true)
+                .required(false)
+                .help("Write each VM's output to <LOG_DIR>/<vm id>-<function>.log, kept only if the VM crashes")
+        )
+        .arg(
+            Arg::with_name("vmm metrics interval")
+                .long("vmm_metrics_interval")
+                .value_name("MS")
+                .takes_value(true)
+                .required(false)
+                .requires("log dir")
+                .help("Collect VMM metrics (vCPU exits, block I/O, serial bytes) of each VM every MS milliseconds")
+        )
+        .arg(
+            Arg::with_name("scratch dir")
+                .long("scratch_dir")
+                .value_name("SCRATCH_DIR")
+                .takes_value(true)
+                .required(false)
+                .default_value("/tmp")
+                .help("Where scratch disks of functions with scratch_mib are created")
+        )
+        .arg(
+            Arg::with_name("pause idle")
+                .long("pause_idle")
+                .takes_value(false)
+                .required(false)
+                .help("Pause the vCPUs of idle VMs and resume them when they get a request")
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["firecracker", "fake"])
+                .default_value("firecracker")
+                .help("Run real Firecracker VMs, or fake VMs that need neither KVM nor root")
+        )
+        .arg(
+            Arg::with_name("fake boot delay")
+                .long("fake_boot_delay")
+                .value_name("MS")
+                .takes_value(true)
+                .required(false)
+                .default_value("100")
+                .help("How long fake VMs take to boot")
+        )
+        .arg(
+            Arg::with_name("fake latency")
+                .long("fake_latency")
+                .value_name("MS")
+                .takes_value(true)
+                .required(false)
+                .default_value("10")
+                .help("How long fake VMs take to answer a request")
+        )
+        .arg(
+            Arg::with_name("fake fail every")
+                .long("fake_fail_every")
+                .value_name("N")
+                .takes_value(true)
+                .required(false)
+                .help("Every Nth request of a fake VM fails")
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .value_name("PROFILE")
+                .takes_value(true)
+                .required(false)
+                .help("Simulate the workload with boot and execution times from a YAML profile or a previous run's output JSON")
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .takes_value(true)
+                .required(false)
+                .default_value("0")
+                .help("Seed for sampling boot and execution times in a simulation, or think times in a closed loop")
+        )
+        .arg(
+            Arg::with_name("closed loop")
+                .long("closed_loop")
+                .value_name("USERS")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with_all(&["requests file", "simulate"])
+                .help("Instead of replaying a requests file, run USERS closed-loop virtual users per function")
+        )
+        .arg(
+            Arg::with_name("think time")
+                .long("think_time")
+                .value_name("MS")
+                .takes_value(true)
+                .required(false)
+                .default_value("1000")
+                .help("Mean of the exponentially distributed time closed-loop users wait between a response and their next request")
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .required(false)
+                .default_value("60")
+                .help("How long closed-loop users send requests")
+        )
+        .arg(
+            Arg::with_name("request timeout")
+                .long("request_timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .required(false)
+                .default_value("60")
+                .help("How long a closed-loop user waits for a response before sending its next request")
+        )
+        .arg(
+            Arg::with_name("boot threads")
+                .long("boot_threads")
+                .value_name("N")
+                .takes_value(true)
+                .required(false)
+                .default_value("4")
+                .help("Threads that launch VMs, so that scheduling doesn't wait for launches. 0 launches VMs while scheduling")
+        )
+        .arg(
+            Arg::with_name("boot timeout")
+                .long("boot_timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .required(false)
+                .default_value("30")
+                .help("How long a VM has to launch and become ready before it counts as a boot failure. 0 waits forever")
+        )
+        .arg(
+            Arg::with_name("drain timeout")
+                .long("drain_timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .required(false)
+                .default_value("30")
+                .help("How long in-flight requests get to complete on shutdown")
+        )
         .get_matches();
 
+    if let Some(args) = cmd_arguments.subcommand_matches("gen-workload") {
+        gen_workload(args);
+        return;
+    }
+    if let Some(args) = cmd_arguments.subcommand_matches("import-azure") {
+        import_azure(args);
+        return;
+    }
+
     let kernel = cmd_arguments.value_of("kernel").unwrap().to_string();
     let cmd_line = cmd_arguments.value_of("command line").unwrap().to_string();
-    let mut requests_file = File::open(cmd_arguments.value_of("requests file").unwrap())
-        .expect("Request file not found");
+    let requests_file = cmd_arguments.value_of("requests file").map(|path| {
+        request::open(path).unwrap_or_else(|e| panic!("Could not open requests file {}: {}", path, e))
+    });
     let runtimefs_dir = cmd_arguments.value_of("runtimefs dir").unwrap();
     let appfs_dir = cmd_arguments.value_of("appfs dir").unwrap();
     let func_config = File::open(cmd_arguments.value_of("function config file").unwrap())
         .expect("Function config file not found");
     let debug = cmd_arguments.is_present("debug");
+    let backend: Box<VmBackend> = match cmd_arguments.value_of("backend").unwrap() {
+        "fake" => Box::new(FakeBackend {
+            boot_delay: Duration::from_millis(cmd_arguments.value_of("fake boot delay").unwrap()
+                                                  .parse::<u64>().expect("Invalid fake boot delay")),
+            latency: Duration::from_millis(cmd_arguments.value_of("fake latency").unwrap()
+                                               .parse::<u64>().expect("Invalid fake latency")),
+            fail_every: cmd_arguments.value_of("fake fail every")
+                .map(|n| n.parse::<u64>().expect("Invalid fake failure interval")),
+        }),
+        _ => Box::new(FirecrackerBackend { debug }),
+    };
+    let profile = cmd_arguments.value_of("simulate").map(|path| {
+        Profile::load(Path::new(path)).unwrap_or_else(|e| panic!("Invalid simulation profile: {}", e))
+    });
+    let mut closed_loop = cmd_arguments.value_of("closed loop").map(|users| {
+        let seconds = |name: &str| Duration::from_secs(cmd_arguments.value_of(name).unwrap()
+                                                           .parse::<u64>().expect("Invalid number of seconds"));
+        ClosedLoop::new(users.parse::<u32>().expect("Invalid number of closed-loop users"),
+                        cmd_arguments.value_of("think time").unwrap().parse::<f64>().expect("Invalid think time"),
+                        seconds("duration"),
+                        seconds("request timeout"),
+                        cmd_arguments.value_of("seed").unwrap().parse::<u64>().expect("Invalid seed"))
+    });
+    let virtual_clock = VirtualClock::default();
+    let (backend, clock): (Box<VmBackend>, Box<Clock>) = match profile {
+        Some(_) => (Box::new(SimBackend), Box::new(virtual_clock.clone())),
+        None => (backend, Box::new(WallClock)),
+    };
     let snapshots = cmd_arguments.value_of("snapshot dir").map(|sd| [sd].iter().collect());
     let mem_size: usize = cmd_arguments.value_of("total memory capacity").unwrap()
                                        .parse::<usize>().unwrap();
+    let cgroup_parent = PathBuf::from(cmd_arguments.value_of("cgroup parent").unwrap());
+    let vmm_mem_overhead = cmd_arguments.value_of("vmm memory overhead").unwrap()
+                                        .parse::<usize>().expect("Invalid VMM memory overhead");
+    let cpu_mode = match cmd_arguments.value_of("cpu mode").unwrap() {
+        "dedicated" => config::CpuMode::Dedicated,
+        _ => config::CpuMode::Shared,
+    };
+    let cpu_policy = match cmd_arguments.value_of("cpu policy").unwrap() {
+        "vcpus" => controller::CpuPolicy::Vcpus,
+        "fixed" => controller::CpuPolicy::Fixed {
+            vcpu_count: cmd_arguments.value_of("vcpus").unwrap().parse::<u64>().expect("Invalid vCPU count"),
+            cpu_share: cmd_arguments.value_of("cpu share").unwrap().parse::<u64>().expect("Invalid CPU share"),
+        },
+        _ => controller::CpuPolicy::Proportional,
+    };
+    let cpu_quota = cmd_arguments.value_of("cpu quota").map(|cpus| match cpus.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 => cpus,
+        _ => panic!("Invalid CPU quota"),
+    });
+    let launch_mode = match cmd_arguments.value_of("launch mode").unwrap() {
+        "exec" => LaunchMode::Exec(cmd_arguments.value_of("vmm binary").map(PathBuf::from).unwrap_or_else(|| {
+            std::env::current_exe().expect("Couldn't find the controller executable")
+                .with_file_name("firerunner-vmm")
+        })),
+        _ => LaunchMode::Fork,
+    };
+    let log_dir = cmd_arguments.value_of("log dir").map(PathBuf::from);
+    if let Some(ref log_dir) = log_dir {
+        std::fs::create_dir_all(log_dir).expect("Could not create log directory");
+    }
+    let vmm_metrics_interval = cmd_arguments.value_of("vmm metrics interval")
+        .map(|ms| Duration::from_millis(ms.parse::<u64>().expect("Invalid VMM metrics interval")));
+    let drain_timeout = Duration::from_secs(cmd_arguments.value_of("drain timeout").unwrap()
+                                                .parse::<u64>().expect("Invalid drain timeout"));
+    let boot_timeout = match cmd_arguments.value_of("boot timeout").unwrap()
+        .parse::<u64>().expect("Invalid boot timeout") {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
     let output_file = cmd_arguments.value_of("output path")
         .map(|fname| Box::new(File::create(fname).expect("Could not create output file")) as Box<Write>).unwrap_or(Box::new(std::io::stdout()));
 
-    // We disable seccomp filtering when testing, because when running the test_gnutests
+    // Seccomp filtering is disabled by default, because when running the test_gnutests
     // integration test from test_unittests.py, an invalid syscall is issued, and we crash
     // otherwise.
-    let seccomp_level = 0;
+    let seccomp_level = cmd_arguments.value_of("seccomp level").unwrap().parse::<u32>().unwrap();
+    let jail = if cmd_arguments.is_present("jail") {
+        Some(JailConfig {
+            chroot_base: PathBuf::from(cmd_arguments.value_of("chroot base").unwrap()),
+            uid: cmd_arguments.value_of("jail uid").unwrap().parse::<u32>().expect("Invalid uid"),
+            gid: cmd_arguments.value_of("jail gid").unwrap().parse::<u32>().expect("Invalid gid"),
+        })
+    } else {
+        None
+    };
 
     // init config
     // Current implementation assumes that function config do not change after controller
@@ -144,56 +635,59 @@ fn main() {
     let app_configs = config::Configuration::new(runtimefs_dir, appfs_dir, func_config);
     println!("{} functions loaded", app_configs.num_func());
 
-    let mut controller = controller::Controller::new(app_configs.clone(),
-                                                     seccomp_level,
-                                                     cmd_line,
-                                                     kernel,
-                                                     debug,
-                                                     snapshots.clone(),
-                                                     mem_size);
+    let settings = controller::ControllerSettings {
+        seccomp_level,
+        cmd_line,
+        kernel,
+        snapshot: snapshots.clone(),
+        mem_size,
+        cgroup_parent,
+        vmm_mem_overhead,
+        cpu_mode,
+        cpu_policy,
+        cpu_quota,
+        jail,
+        launch_mode,
+        log_dir,
+        vmm_metrics_interval,
+        pause_idle: cmd_arguments.is_present("pause idle"),
+        scratch_dir: PathBuf::from(cmd_arguments.value_of("scratch dir").unwrap()),
+        boot_threads: cmd_arguments.value_of("boot threads").unwrap()
+            .parse::<usize>().expect("Invalid number of boot threads"),
+        boot_timeout,
+    };
+    let mut controller = controller::Controller::new(app_configs.clone(), backend, clock, settings);
     println!("{:?}", controller.get_cluster_info());
 
-    controller.ignite();
-
-    std::thread::sleep(std::time::Duration::from_secs(1));
-
-    let mut request_schedule_latency: Vec<u64> = Vec::new();
-
-    let workload_start = time::precise_time_ns();
-
-    let num_requests = std::io::BufReader::new(&mut requests_file).lines().count();
-
-    requests_file.seek(SeekFrom::Start(0)).expect("Couldn't seek to start of request file");
-
-    let progress = ProgressBar::new(num_requests as u64);
-    for line in std::io::BufReader::new(requests_file).lines().map(|l| l.unwrap()) {
-        match request::parse_json(line) {
-            Ok(req) => {
-                // Check function existence at the gateway
-                if !app_configs.exist(&req.function){
-//                    println!("function {} doesn't exist", &req.function);
-                    continue;
-                }
-
-                let timestamp = req.timestamp;
-                let left = (timestamp * 1000000).checked_sub(time::precise_time_ns() - workload_start).unwrap_or(0);
-                std::thread::sleep(std::time::Duration::from_nanos(left));
-
-                let t1 = time::precise_time_ns();
-                controller.schedule(req);
-                let t2 = time::precise_time_ns();
-                request_schedule_latency.push(t2-t1);
-                progress.inc(1);
-            },
-            Err(e) => panic!("Invalid request: {:?}", e)
-        }
+    // SIGINT and SIGTERM trigger a graceful shutdown. They are blocked before any other thread
+    // is spawned so that only the signal thread receives them.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGINT);
+        signals.add(Signal::SIGTERM);
+        signals.thread_block().expect("Failed to block signals");
+        let interrupted = interrupted.clone();
+        std::thread::spawn(move || {
+            if let Ok(signal) = signals.wait() {
+                println!("Received {:?}, shutting down", signal);
+                interrupted.store(true, Ordering::SeqCst);
+            }
+        });
     }
 
-    while controller.check_running() > 0 {
-        std::thread::sleep(std::time::Duration::from_millis(200));
-    }
+    let (workload_start, workload_end) = match (profile, closed_loop.as_mut()) {
+        (_, Some(closed_loop)) => closed_loop.run(&mut controller, &app_configs, &interrupted),
+        (Some(profile), None) => {
+            let seed = cmd_arguments.value_of("seed").unwrap().parse::<u64>().expect("Invalid seed");
+            let mut simulation = Simulation::new(profile, seed, virtual_clock, &app_configs)
+                .unwrap_or_else(|e| panic!("Can't simulate the workload: {}", e));
+            simulation.run(&mut controller, requests_file.unwrap(), &app_configs, &interrupted)
+        },
+        (None, None) => replay(&mut controller, requests_file.unwrap(), &app_configs, &interrupted),
+    };
 
-    let workload_end = time::precise_time_ns();
+    controller.shutdown(drain_timeout);
 
     let total_time = (workload_end - workload_start) / 1_000_000; // in ms
     let num_complete = controller.get_stat().num_complete;
@@ -201,35 +695,180 @@ fn main() {
     let num_drop_concurrency = controller.get_stat().num_drop_concurrency;
     let num_vm = controller.get_stat().boot_timestamp.len();
     let num_evict = controller.get_stat().eviction_timestamp.len();
+    let stat = controller.get_stat();
     let throughput = (num_complete as f32) / (total_time as f32 / 1000f32);
 
     // Output time measurement as a json string
     let res = json!({
+        "backend": cmd_arguments.value_of("backend").unwrap(),
+        "simulation profile": cmd_arguments.value_of("simulate"),
         "snapshot": snapshots.is_some(),
+        "jailed": cmd_arguments.is_present("jail"),
+        "seccomp level": seccomp_level,
+        "launch mode": cmd_arguments.value_of("launch mode").unwrap(),
         "total cpu": controller.get_cluster_info().total_cpu,
         "total mem": controller.get_cluster_info().total_mem,
         "app config file": cmd_arguments.value_of("function config file").unwrap(),
-        "requests file": cmd_arguments.value_of("requests file").unwrap(),
+        "requests file": cmd_arguments.value_of("requests file"),
+        "closed loop": closed_loop.as_ref().map(|c| c.summary()),
         "start time": workload_start,
         "end time": workload_end,
         "boot timestamps": controller.get_stat().boot_timestamp,
         "request/response timestamps": controller.get_stat().request_response_timestamp,
         "eviction timestamps": controller.get_stat().eviction_timestamp,
+        "paused idle vms": cmd_arguments.is_present("pause idle"),
+        "resume latencies": stat.resume_latency,
+        "vm functions": stat.vm_function,
         "vm mem sizes": controller.get_stat().vm_mem_size,
+        "cpu policy": cmd_arguments.value_of("cpu policy").unwrap(),
+        "cpu quota": cpu_quota,
+        "vm vcpu counts": stat.vm_vcpu_count,
+        "vm cpu shares": stat.vm_cpu_share,
+        "vm cpus": stat.vm_cpus,
+        "vmm metrics": stat.vmm_metrics,
         "drop requests (resource)": num_drop_resource,
         "drop requests (concurrency)": num_drop_concurrency,
         "number of evictions": num_evict,
         "number of vms booted": num_vm,
+        "boot failures": stat.num_boot_fail,
+        "vm crashes": stat.num_vm_crash,
+        "stale responses": stat.num_stale_response,
         "number of completed requests": num_complete,
+        "snapshot hits": stat.num_snapshot_hit,
+        "snapshot misses": stat.num_snapshot_miss,
+        "malformed requests": stat.num_malformed,
+        "requests for unknown functions": stat.num_unknown_function,
+        "boot latency (cold)": stat.boot_latencies(metrics::BootMode::Cold),
+        "boot latency (snapshot)": stat.boot_latencies(metrics::BootMode::Snapshot),
+        "boot latency (function snapshot)": stat.boot_latencies(metrics::BootMode::FunctionSnapshot),
         "cumulative throughput": throughput,
-        "request schedule latency": request_schedule_latency
+        "boot threads": cmd_arguments.value_of("boot threads").unwrap(),
+        "boot timeout": cmd_arguments.value_of("boot timeout").unwrap(),
+        "request schedule latency": stat.schedule_latency,
+        "dispatch lag": stat.dispatch_lag
     });
 
 
     if let Err(e) = serde_json::to_writer_pretty(output_file, &res) {
         panic!("failed to write measurement results as json: {}", e.description());
     }
+}
+
+fn gen_workload(args: &clap::ArgMatches) {
+    let config_file = File::open(args.value_of("workload config").unwrap())
+        .expect("Workload config not found");
+    let config: workload::WorkloadConfig = serde_yaml::from_reader(config_file)
+        .unwrap_or_else(|e| panic!("Invalid workload config: {}", e));
+    let seed = args.value_of("seed").unwrap().parse::<u64>().expect("Invalid seed");
+    let written = match args.value_of("output") {
+        Some(path) => workload::generate(&config, seed, File::create(path).expect("Could not create output file")),
+        None => workload::generate(&config, seed, std::io::stdout()),
+    };
+    match written {
+        Ok(count) => eprintln!("{} requests generated", count),
+        Err(e) => panic!("Failed to write requests: {}", e),
+    }
+}
 
-    controller.kill_all();
+fn import_azure(args: &clap::ArgMatches) {
+    let open = |name: &str| {
+        args.value_of(name).map(|path| {
+            std::io::BufReader::new(File::open(path).unwrap_or_else(|e| panic!("{}: {}", path, e)))
+        })
+    };
+    let func_config = File::open(args.value_of("function config file").unwrap())
+        .expect("Function config file not found");
+    // only the functions' names, memory and users matter
+    let app_configs = config::Configuration::new("", "", func_config);
+    let memory = open("memory").map(azure::read_memory).unwrap_or(Ok(Default::default()))
+        .unwrap_or_else(|e| panic!("Invalid memory CSV: {}", e));
+    let durations = open("durations").map(azure::read_durations).unwrap_or(Ok(Default::default()))
+        .unwrap_or_else(|e| panic!("Invalid durations CSV: {}", e));
+    let mapping = match args.value_of("mapping").unwrap() {
+        "memory" => azure::Mapping::Memory,
+        _ => azure::Mapping::RoundRobin,
+    };
+    let time_scale = args.value_of("time scale").unwrap().parse::<f64>().expect("Invalid time scale");
+    let minutes = args.value_of("minutes").map(|m| m.parse::<usize>().expect("Invalid number of minutes"));
+    let seed = args.value_of("seed").unwrap().parse::<u64>().expect("Invalid seed");
+
+    let mut importer = azure::Importer::new(&app_configs, mapping, memory, durations);
+    let invocations = open("invocations").unwrap();
+    let written = match args.value_of("output") {
+        Some(path) => importer.import(invocations, File::create(path).expect("Could not create output file"),
+                                      time_scale, seed, minutes),
+        None => importer.import(invocations, std::io::stdout(), time_scale, seed, minutes),
+    };
+    match written {
+        Ok(count) => eprintln!("{} requests imported", count),
+        Err(e) => panic!("Failed to import trace: {}", e),
+    }
+}
+
+// Send the requests to `controller` at their timestamps, as they are read. Returns when the
+// workload started and ended.
+fn replay<R: BufRead>(controller: &mut controller::Controller, requests_file: R, app_configs: &config::Configuration,
+                      interrupted: &AtomicBool) -> (u64, u64) {
+    controller.ignite();
+
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    let workload_start = time::precise_time_ns();
+
+    // the number of requests isn't known up front
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(ProgressStyle::default_spinner().template("{spinner} {pos} requests sent [{elapsed}]"));
+    let mut requests = request::Requests::new(requests_file);
+    while let Some(req) = requests.next() {
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        // Check function existence at the gateway
+        if !app_configs.exist(&req.function){
+            controller.update_stat(|stat| stat.unknown_function_req(1));
+            continue;
+        }
+
+        let timestamp = req.timestamp;
+        let left = (timestamp * 1000000).checked_sub(time::precise_time_ns() - workload_start).unwrap_or(0);
+        if !sleep_unless_interrupted(&interrupted, Duration::from_nanos(left)) {
+            break;
+        }
+
+        // the scheduler measures how long scheduling takes, there's no need to wait for it
+        let lag = time::precise_time_ns().saturating_sub(workload_start + timestamp * 1000000);
+        controller.update_stat(move |stat| stat.log_dispatch_lag(lag));
+        controller.dispatch(req);
+        progress.inc(1);
+    }
+    progress.finish();
+    let malformed = requests.malformed as u32;
+    controller.update_stat(move |stat| stat.malformed_req(malformed));
+
+    while controller.check_running() > 0 && !interrupted.load(Ordering::SeqCst) {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+
+    let workload_end = time::precise_time_ns();
+
+    (workload_start, workload_end)
+}
+
+// Sleep for `duration`, waking up early if `interrupted` gets set.
+// Returns false if interrupted.
+fn sleep_unless_interrupted(interrupted: &AtomicBool, duration: Duration) -> bool {
+    let step = Duration::from_millis(100);
+    let deadline = Instant::now() + duration;
+    loop {
+        if interrupted.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        let left = deadline - now;
+        std::thread::sleep(if left < step { left } else { step });
+    }
 }
 
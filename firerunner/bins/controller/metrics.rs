@@ -1,5 +1,50 @@
 use std::collections::btree_map::BTreeMap;
 
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootMode {
+    Cold,
+    Snapshot,          // runtime snapshot
+    FunctionSnapshot,  // snapshot with the function's code loaded
+}
+
+// VMM counters of a VM, summed over all its metrics flushes
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VmmMetrics {
+    pub flushes: u64,
+    pub vcpu_exit_io_in: u64,
+    pub vcpu_exit_io_out: u64,
+    pub vcpu_exit_mmio_read: u64,
+    pub vcpu_exit_mmio_write: u64,
+    pub block_read_count: u64,
+    pub block_write_count: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+    pub serial_read_bytes: u64,
+    pub serial_write_bytes: u64,
+}
+
+impl VmmMetrics {
+    // Add one flush of the VMM's metrics
+    pub fn add(&mut self, sample: &Value) {
+        let counter = |group: &str, name: &str| sample[group][name].as_u64().unwrap_or(0);
+        self.flushes += 1;
+        self.vcpu_exit_io_in += counter("vcpu", "exit_io_in");
+        self.vcpu_exit_io_out += counter("vcpu", "exit_io_out");
+        self.vcpu_exit_mmio_read += counter("vcpu", "exit_mmio_read");
+        self.vcpu_exit_mmio_write += counter("vcpu", "exit_mmio_write");
+        self.block_read_count += counter("block", "read_count");
+        self.block_write_count += counter("block", "write_count");
+        self.block_read_bytes += counter("block", "read_bytes");
+        self.block_write_bytes += counter("block", "write_bytes");
+        // the serial device counts bytes
+        self.serial_read_bytes += counter("uart", "read_count");
+        self.serial_write_bytes += counter("uart", "write_count");
+    }
+}
+
 #[derive(Clone)]
 pub struct Metrics {
     pub num_drop: u32,  // number of dropped requests
@@ -7,9 +52,25 @@ pub struct Metrics {
     pub num_drop_concurrency: u32,
     pub num_complete: u32,  // number of requests completed
     pub num_evict: u32, 
+    pub num_boot_fail: u32, // VMs that failed to launch or didn't become ready in time
+    pub num_vm_crash: u32,  // ready VMs whose connection broke or could not be served
+    pub num_stale_response: u32, // responses from VMs the controller already let go of
+    pub num_snapshot_hit: u32,   // VMs booted from a snapshot
+    pub num_snapshot_miss: u32,  // VMs cold booted because no matching snapshot exists
+    pub num_malformed: u32,  // requests skipped because they could not be parsed
+    pub num_unknown_function: u32,  // requests skipped because their function isn't configured
+    pub vm_function: BTreeMap<u32, String>,
     pub vm_mem_size: BTreeMap<u32, usize>,
+    pub vm_vcpu_count: BTreeMap<u32, u64>,
+    pub vm_cpu_share: BTreeMap<u32, u64>,
+    pub vm_cpus: BTreeMap<u32, Vec<usize>>, // dedicated CPUs of pinned VMs
+    pub vm_boot_mode: BTreeMap<u32, BootMode>,
+    pub vmm_metrics: BTreeMap<u32, VmmMetrics>,
     pub boot_timestamp: BTreeMap<u32, Vec<u64>>, // key is vm_id, value is boot timestamp
     pub eviction_timestamp: BTreeMap<u32, Vec<u64>>,
+    pub dispatch_lag: Vec<u64>, // how long after its timestamp each request was scheduled, in ns
+    pub schedule_latency: Vec<u64>, // how long scheduling each request took, in ns
+    pub resume_latency: BTreeMap<u32, Vec<u64>>, // key is vm_id, value is how long each resume took in ns
     pub request_response_timestamp: BTreeMap<u32, Vec<u64>> // key is vm_id, value is request send time and response receive time
 }
 
@@ -21,9 +82,25 @@ impl Metrics {
             num_drop_concurrency: 0,
             num_complete: 0,
             num_evict: 0,
+            num_boot_fail: 0,
+            num_vm_crash: 0,
+            num_stale_response: 0,
+            num_snapshot_hit: 0,
+            num_snapshot_miss: 0,
+            num_malformed: 0,
+            num_unknown_function: 0,
             boot_timestamp: Default::default(),
+            vm_function: Default::default(),
             vm_mem_size: Default::default(),
+            vm_vcpu_count: Default::default(),
+            vm_cpu_share: Default::default(),
+            vm_cpus: Default::default(),
+            vm_boot_mode: Default::default(),
+            vmm_metrics: Default::default(),
             eviction_timestamp: Default::default(),
+            dispatch_lag: Default::default(),
+            schedule_latency: Default::default(),
+            resume_latency: Default::default(),
             request_response_timestamp: Default::default(),
         }
     }
@@ -44,18 +121,75 @@ impl Metrics {
         self.num_complete = self.num_complete + num;
     }
 
+    pub fn malformed_req(&mut self, num: u32) {
+        self.num_malformed = self.num_malformed + num;
+    }
+
+    pub fn unknown_function_req(&mut self, num: u32) {
+        self.num_unknown_function = self.num_unknown_function + num;
+    }
+
     pub fn evict_vm(&mut self, num: u32) {
         self.num_evict= self.num_evict+ num;
     }
 
+    pub fn boot_fail(&mut self, num: u32) {
+        self.num_boot_fail = self.num_boot_fail + num;
+    }
+
+    pub fn vm_crash(&mut self, num: u32) {
+        self.num_vm_crash = self.num_vm_crash + num;
+    }
+
+    pub fn stale_response(&mut self, num: u32) {
+        self.num_stale_response = self.num_stale_response + num;
+    }
+
+    pub fn snapshot_lookup(&mut self, hit: bool) {
+        if hit {
+            self.num_snapshot_hit = self.num_snapshot_hit + 1;
+        } else {
+            self.num_snapshot_miss = self.num_snapshot_miss + 1;
+        }
+    }
+
+    pub fn log_boot_mode(&mut self, vm_id: u32, mode: BootMode) {
+        self.vm_boot_mode.insert(vm_id, mode);
+    }
+
+    // Boot latency (ns) of every VM that booted in `mode` and became ready
+    pub fn boot_latencies(&self, mode: BootMode) -> Vec<u64> {
+        self.boot_timestamp.iter().filter(|(id, _)| {
+            self.vm_boot_mode.get(*id) == Some(&mode)
+        }).filter_map(|(_, tsps)| {
+            if tsps.len() >= 2 { Some(tsps[1] - tsps[0]) } else { None }
+        }).collect()
+    }
+
     pub fn log_boot_timestamp(&mut self, vm_id: u32, tsp: u64) {
         self.boot_timestamp.entry(vm_id).or_insert(Vec::new()).push(tsp);
     }
 
+    pub fn log_vm_function(&mut self, vm_id: u32, function: &String) {
+        self.vm_function.insert(vm_id, function.clone());
+    }
+
     pub fn log_vm_mem_size(&mut self, vm_id: u32, mem: usize) {
             self.vm_mem_size.entry(vm_id).or_insert(mem);
     }
 
+    pub fn log_vm_cpu(&mut self, vm_id: u32, vcpu_count: u64, cpu_share: u64, cpus: Option<&Vec<usize>>) {
+        self.vm_vcpu_count.insert(vm_id, vcpu_count);
+        self.vm_cpu_share.insert(vm_id, cpu_share);
+        if let Some(cpus) = cpus {
+            self.vm_cpus.insert(vm_id, cpus.clone());
+        }
+    }
+
+    pub fn log_vmm_metrics(&mut self, vm_id: u32, sample: &Value) {
+        self.vmm_metrics.entry(vm_id).or_insert(Default::default()).add(sample);
+    }
+
     pub fn log_request_timestamp(&mut self, vm_id: u32, tsp: u64) {
         self.request_response_timestamp.entry(vm_id).or_insert(Vec::new()).push(tsp);
 
@@ -64,4 +198,16 @@ impl Metrics {
     pub fn log_eviction_timestamp(&mut self, vm_id: u32, tsp: u64) {
         self.eviction_timestamp.entry(vm_id).or_insert(Vec::new()).push(tsp);
     }
+
+    pub fn log_dispatch_lag(&mut self, lag: u64) {
+        self.dispatch_lag.push(lag);
+    }
+
+    pub fn log_schedule_latency(&mut self, latency: u64) {
+        self.schedule_latency.push(latency);
+    }
+
+    pub fn log_resume_latency(&mut self, vm_id: u32, latency: u64) {
+        self.resume_latency.entry(vm_id).or_insert(Vec::new()).push(latency);
+    }
 }
@@ -1,14 +1,85 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use flate2::read::MultiGzDecoder;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+// How many malformed lines are reported before the rest are only counted
+const MAX_REPORTED_MALFORMED: u64 = 10;
+
 #[derive(Serialize, Deserialize)]
 pub struct Request {
     pub timestamp: u64,
     pub function: String,
     pub payload: Value,
     pub user_id: u32,
+    // set by closed-loop users to match responses to their requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
 }
 
 pub fn parse_json(json: String) -> Result<Request, serde_json::Error> {
     serde_json::from_str(json.as_str())
 }
+
+// Open a requests file for streaming. "-" is stdin, and files ending in .gz are decompressed
+// as they are read. The file is read once from the start, so it can also be a FIFO.
+pub fn open(path: &str) -> io::Result<Box<BufRead>> {
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(path)?;
+    if path.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// The requests of a JSON-lines stream. Blank lines are ignored and malformed ones skipped and
+// counted. The stream ends at the first read error.
+pub struct Requests<R> {
+    lines: io::Lines<R>,
+    line: u64,
+    pub malformed: u64,
+}
+
+impl<R: BufRead> Requests<R> {
+    pub fn new(reader: R) -> Requests<R> {
+        Requests {
+            lines: reader.lines(),
+            line: 0,
+            malformed: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Requests<R> {
+    type Item = Request;
+
+    fn next(&mut self) -> Option<Request> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    println!("Failed to read requests after line {}: {}", self.line, e);
+                    return None;
+                },
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_json(line) {
+                Ok(req) => return Some(req),
+                Err(e) => {
+                    self.malformed += 1;
+                    if self.malformed <= MAX_REPORTED_MALFORMED {
+                        println!("Skipping malformed request on line {}: {}", self.line, e);
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+// Discrete-event simulation of a workload. Requests go through the controller's scheduling and
+// cluster accounting as in a real run, but VMs only exist in the controller's bookkeeping
+// (`backend::SimBackend`) and time is a `VirtualClock`. Boot and execution times are sampled
+// from per-function distributions, given in a profile or taken from a previous run's output.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::File;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicBool};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::clock::{Clock, VirtualClock};
+use super::config::Configuration;
+use super::controller::{Controller, Placement};
+use super::request;
+
+// In milliseconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    Constant(f64),
+    Uniform { min: f64, max: f64 },
+    Exponential { mean: f64 },
+    Normal { mean: f64, stddev: f64 },
+    Samples(Vec<f64>),
+}
+
+impl Distribution {
+    fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        let ms = match self {
+            Distribution::Constant(ms) => *ms,
+            Distribution::Uniform { min, max } => min + (max - min) * rng.gen::<f64>(),
+            Distribution::Exponential { mean } => -mean * (1.0 - rng.gen::<f64>()).ln(),
+            Distribution::Normal { mean, stddev } => {
+                // Box-Muller
+                let (u1, u2) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+                mean + stddev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            },
+            Distribution::Samples(samples) => samples[rng.gen_range(0, samples.len())],
+        };
+        ms.max(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FunctionProfile {
+    pub boot_ms: Distribution,
+    pub exec_ms: Distribution,
+}
+
+// Functions without a profile of their own use `default`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Profile {
+    pub default: Option<FunctionProfile>,
+    #[serde(default)]
+    pub functions: BTreeMap<String, FunctionProfile>,
+}
+
+impl Profile {
+    // A YAML profile, or the output JSON of a previous run if `path` ends in .json
+    pub fn load(path: &Path) -> Result<Profile, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            let output: Value = serde_json::from_reader(file).map_err(|e| e.to_string())?;
+            Profile::from_output(&output)
+        } else {
+            serde_yaml::from_reader(file).map_err(|e| e.to_string())
+        }
+    }
+
+    // Use the measured boot and execution times of each function as samples. The default
+    // profile pools the samples of all functions.
+    fn from_output(output: &Value) -> Result<Profile, String> {
+        let functions = output["vm functions"].as_object()
+            .ok_or("output has no \"vm functions\", it is from an older controller")?;
+        let mut boot: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        let mut exec: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        for (vm, function) in functions {
+            let function = function.as_str().ok_or("invalid \"vm functions\"")?.to_string();
+            let durations = |key: &str| -> Vec<f64> {
+                let timestamps: Vec<u64> = output[key][vm].as_array()
+                    .map(|ts| ts.iter().filter_map(|t| t.as_u64()).collect())
+                    .unwrap_or(Vec::new());
+                timestamps.chunks(2).filter(|pair| pair.len() == 2)
+                    .map(|pair| (pair[1] - pair[0]) as f64 / 1_000_000.0)
+                    .collect()
+            };
+            // only the first pair of boot timestamps is launch and ready
+            boot.entry(function.clone()).or_insert(Vec::new()).extend(durations("boot timestamps").into_iter().take(1));
+            exec.entry(function).or_insert(Vec::new()).extend(durations("request/response timestamps"));
+        }
+
+        let mut profile = Profile { default: None, functions: BTreeMap::new() };
+        let (mut all_boot, mut all_exec) = (Vec::new(), Vec::new());
+        for (function, boot) in boot {
+            let exec = exec.remove(&function).unwrap_or(Vec::new());
+            all_boot.extend_from_slice(&boot);
+            all_exec.extend_from_slice(&exec);
+            if !boot.is_empty() && !exec.is_empty() {
+                profile.functions.insert(function, FunctionProfile {
+                    boot_ms: Distribution::Samples(boot),
+                    exec_ms: Distribution::Samples(exec),
+                });
+            }
+        }
+        if !all_boot.is_empty() && !all_exec.is_empty() {
+            profile.default = Some(FunctionProfile {
+                boot_ms: Distribution::Samples(all_boot),
+                exec_ms: Distribution::Samples(all_exec),
+            });
+        }
+        Ok(profile)
+    }
+
+    fn get(&self, function: &String) -> Option<&FunctionProfile> {
+        self.functions.get(function).or(self.default.as_ref())
+    }
+}
+
+// A simulated VM becoming ready or finishing its request
+struct Event {
+    time: u64,
+    seq: u64, // events at the same time happen in the order they were scheduled
+    vm: u32,
+    user_id: u32,
+    function: String,
+    ready: bool,
+}
+
+// Ordered so that `BinaryHeap` pops the earliest event first
+impl Ord for Event {
+    fn cmp(&self, other: &Event) -> Ordering {
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Event) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for Event {}
+
+pub struct Simulation {
+    profile: Profile,
+    rng: StdRng,
+    clock: VirtualClock, // shared with the controller
+    events: BinaryHeap<Event>,
+    seq: u64,
+}
+
+impl Simulation {
+    pub fn new(profile: Profile, seed: u64, clock: VirtualClock, configs: &Configuration) -> Result<Simulation, String> {
+        if let Some(function) = configs.configs.keys().find(|f| profile.get(f).is_none()) {
+            return Err(format!("no boot and execution times for function {}", function));
+        }
+        Ok(Simulation {
+            profile,
+            rng: StdRng::seed_from_u64(seed),
+            clock,
+            events: BinaryHeap::new(),
+            seq: 0,
+        })
+    }
+
+    // Replay `requests` against `controller`, which has to use this simulation's clock and
+    // `SimBackend`. Returns the virtual start and end time of the workload.
+    pub fn run<R: BufRead>(&mut self, controller: &mut Controller, requests: R, configs: &Configuration,
+                           interrupted: &AtomicBool) -> (u64, u64) {
+        let start = self.clock.now_ns();
+
+        let mut requests = request::Requests::new(requests);
+        while let Some(req) = requests.next() {
+            if interrupted.load(atomic::Ordering::SeqCst) {
+                break;
+            }
+            if !configs.exist(&req.function) {
+                controller.update_stat(|stat| stat.unknown_function_req(1));
+                continue;
+            }
+
+            let arrival = start + req.timestamp * 1_000_000;
+            self.run_until(controller, arrival);
+            if arrival > self.clock.now_ns() {
+                self.clock.set(arrival);
+            }
+
+            let (user_id, function) = (req.user_id, req.function.clone());
+            match controller.schedule(req) {
+                Placement::Warm(vm) => self.execute(controller, vm, user_id, function),
+                Placement::Booted(vm) => {
+                    let boot = self.sample(&function, |p| &p.boot_ms);
+                    self.push(boot, vm, user_id, function, true);
+                },
+                Placement::Dropped => (),
+            }
+        }
+        let malformed = requests.malformed as u32;
+        controller.update_stat(move |stat| stat.malformed_req(malformed));
+        self.run_until(controller, std::u64::MAX);
+
+        (start, self.clock.now_ns())
+    }
+
+    // Process all events up to `time`
+    fn run_until(&mut self, controller: &mut Controller, time: u64) {
+        while self.events.peek().map(|e| e.time <= time).unwrap_or(false) {
+            let event = self.events.pop().unwrap();
+            self.clock.set(event.time);
+            let (vm, at) = (event.vm, event.time);
+            if event.ready {
+                controller.update_stat(move |stat| stat.log_boot_timestamp(vm, at));
+                self.execute(controller, event.vm, event.user_id, event.function);
+            } else {
+                controller.update_stat(move |stat| stat.log_request_timestamp(vm, at));
+                controller.complete(event.vm, event.user_id, event.function);
+            }
+        }
+    }
+
+    // Start running a request on a ready VM
+    fn execute(&mut self, controller: &Controller, vm: u32, user_id: u32, function: String) {
+        let now = self.clock.now_ns();
+        controller.update_stat(move |stat| stat.log_request_timestamp(vm, now));
+        let exec = self.sample(&function, |p| &p.exec_ms);
+        self.push(exec, vm, user_id, function, false);
+    }
+
+    fn sample<F: Fn(&FunctionProfile) -> &Distribution>(&mut self, function: &String, dist: F) -> u64 {
+        let profile = self.profile.get(function).expect("function without a profile");
+        (dist(profile).sample(&mut self.rng) * 1_000_000.0) as u64
+    }
+
+    fn push(&mut self, delay: u64, vm: u32, user_id: u32, function: String, ready: bool) {
+        self.seq += 1;
+        self.events.push(Event {
+            time: self.clock.now_ns() + delay,
+            seq: self.seq,
+            vm,
+            user_id,
+            function,
+            ready,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::backend::SimBackend;
+    use super::super::controller::ControllerSettings;
+    use super::super::metrics::Metrics;
+    use super::super::workload::{self, WorkloadConfig};
+
+    const FUNCTIONS: &str = "
+- {name: a, runtimefs: none, appfs: none, runtime: none, users: 2, vcpus: 1, memory: 128, concurrency_limit: 2}
+- {name: b, runtimefs: none, appfs: none, runtime: none, users: 1, vcpus: 1, memory: 128, concurrency_limit: 2}
+";
+
+    const PROFILE: &str = "
+default: {boot_ms: {exponential: {mean: 150}}, exec_ms: {uniform: {min: 10, max: 300}}}
+functions:
+  b: {boot_ms: {normal: {mean: 300, stddev: 50}}, exec_ms: {samples: [5, 50, 500]}}
+";
+
+    const WORKLOAD: &str = "
+start_time: 0
+end_time: 60000
+num_users: 2
+functions:
+  - {name: a, mu: 200}
+  - {name: b, mu: 100, users: 1}
+";
+
+    // Simulate WORKLOAD on 256 MB, room for two VMs, so that VMs get evicted and requests dropped
+    fn simulate(profile: Profile, seed: u64) -> Metrics {
+        let configs = Configuration::new("", "", FUNCTIONS.as_bytes());
+        let mut requests = Vec::new();
+        let config: WorkloadConfig = serde_yaml::from_str(WORKLOAD).unwrap();
+        workload::generate(&config, 1, &mut requests).unwrap();
+
+        let clock = VirtualClock::default();
+        let settings = ControllerSettings { mem_size: 256, ..Default::default() };
+        let mut controller = Controller::new(configs.clone(), Box::new(SimBackend), Box::new(clock.clone()), settings);
+        let mut simulation = Simulation::new(profile, seed, clock, &configs).unwrap();
+        simulation.run(&mut controller, &requests[..], &configs, &AtomicBool::new(false));
+        controller.shutdown(Duration::from_secs(1));
+        controller.get_stat()
+    }
+
+    // The metrics that only depend on virtual time
+    fn outcome(stat: &Metrics) -> (Vec<u32>, &BTreeMap<u32, String>, &BTreeMap<u32, Vec<u64>>,
+                                   &BTreeMap<u32, Vec<u64>>, &BTreeMap<u32, Vec<u64>>) {
+        (vec![stat.num_complete, stat.num_drop, stat.num_drop_resource, stat.num_drop_concurrency, stat.num_evict],
+         &stat.vm_function, &stat.boot_timestamp, &stat.request_response_timestamp, &stat.eviction_timestamp)
+    }
+
+    #[test]
+    fn same_seed_same_metrics() {
+        let profile: Profile = serde_yaml::from_str(PROFILE).unwrap();
+        let stat = simulate(profile.clone(), 3);
+        assert!(stat.num_complete > 0);
+        assert!(stat.num_drop > 0);
+        assert!(stat.num_evict > 0);
+
+        assert_eq!(outcome(&simulate(profile.clone(), 3)), outcome(&stat));
+        assert_ne!(simulate(profile, 4).boot_timestamp, stat.boot_timestamp);
+    }
+    #[test]
+    fn profiles_from_controller_output() {
+        // from `controller --backend fake --fconfig <FUNCTIONS> --mem_size 512`, 100 ms boots
+        // and 10 ms requests, with 16 requests of `gen-workload`
+        let output = concat!(env!("CARGO_MANIFEST_DIR"), "/../workloads/fake-output.json");
+        let profile = Profile::load(Path::new(output)).unwrap();
+
+        let samples = |dist: &Distribution| match dist {
+            Distribution::Samples(samples) => samples.clone(),
+            dist => panic!("{:?} instead of samples", dist),
+        };
+        // one boot per VM, and a request/response pair per completed request
+        let a = &profile.functions["a"];
+        let b = &profile.functions["b"];
+        assert_eq!((samples(&a.boot_ms).len(), samples(&a.exec_ms).len()), (3, 11));
+        assert_eq!((samples(&b.boot_ms).len(), samples(&b.exec_ms).len()), (1, 4));
+        let default = profile.default.as_ref().unwrap();
+        assert_eq!((samples(&default.boot_ms).len(), samples(&default.exec_ms).len()), (4, 15));
+        assert!(samples(&default.boot_ms).iter().all(|&ms| ms >= 100.0 && ms < 200.0));
+        assert!(samples(&default.exec_ms).iter().all(|&ms| ms >= 10.0 && ms < 100.0));
+
+        let stat = simulate(profile, 1);
+        assert!(stat.num_complete > 0);
+    }
+}
@@ -0,0 +1,120 @@
+// Index of the snapshots available under `--snapshot_dir`.
+// Snapshots are validated once at startup so that a missing or stale snapshot results in a
+// regular cold boot instead of a VM failing to load it.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use firerunner::snapshot::{self, SnapshotMetadata};
+
+use super::config;
+
+pub struct SnapshotCache {
+    runtime_snapshots: BTreeMap<String, (PathBuf, SnapshotMetadata)>,
+    function_snapshots: BTreeMap<String, (PathBuf, SnapshotMetadata)>, // keyed by function name
+    mismatched: BTreeSet<(PathBuf, u64)>, // snapshots already reported as having the wrong vCPU count
+}
+
+impl SnapshotCache {
+    pub fn new<P: AsRef<Path>>(dir: P, kernel: &str, function_configs: &config::Configuration) -> SnapshotCache {
+        let mut cache = SnapshotCache {
+            runtime_snapshots: BTreeMap::new(),
+            function_snapshots: BTreeMap::new(),
+            mismatched: BTreeSet::new(),
+        };
+
+        let entries = match fs::read_dir(dir.as_ref()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Couldn't read snapshot directory {}: {}", dir.as_ref().display(), e);
+                return cache;
+            }
+        };
+        let kernel = fs::canonicalize(kernel).ok();
+        let kernel_size = kernel.as_ref().and_then(|k| fs::metadata(k).ok()).map(|m| m.len());
+        // appfs hashes are only computed for functions that have a snapshot
+        let mut appfs_hashes = BTreeMap::new();
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = match SnapshotMetadata::load(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    println!("Ignoring snapshot {}: invalid metadata ({})", name, e);
+                    continue;
+                }
+            };
+            if Some(&metadata.kernel) != kernel.as_ref() || Some(metadata.kernel_size) != kernel_size {
+                println!("Ignoring snapshot {}: created with kernel {}", name, metadata.kernel.display());
+                continue;
+            }
+
+            match (metadata.function.clone(), metadata.appfs_hash) {
+                (Some(function), Some(hash)) => {
+                    let config = match function_configs.get(&function) {
+                        Some(config) => config,
+                        None => {
+                            println!("Ignoring snapshot {}: unknown function {}", name, function);
+                            continue;
+                        }
+                    };
+                    let current_hash = appfs_hashes.entry(function.clone())
+                        .or_insert_with(|| snapshot::appfs_hash(&config.appfs).ok());
+                    if snapshot::function_snapshot_name(&function, hash) != name {
+                        println!("Ignoring snapshot {}: metadata is for {}", name,
+                                 snapshot::function_snapshot_name(&function, hash));
+                    } else if *current_hash != Some(hash) {
+                        println!("Ignoring snapshot {}: appfs {} changed", name, config.appfs.display());
+                    } else if config.runtime != metadata.runtime {
+                        println!("Ignoring snapshot {}: created with runtime {}", name, metadata.runtime);
+                    } else {
+                        cache.function_snapshots.insert(function, (path, metadata));
+                    }
+                },
+                (None, _) => {
+                    if snapshot::runtime_snapshot_name(&metadata.runtime, metadata.mem_size_mib) != name {
+                        println!("Ignoring snapshot {}: metadata is for {}-{}",
+                                 name, metadata.runtime, metadata.mem_size_mib);
+                    } else {
+                        cache.runtime_snapshots.insert(name, (path, metadata));
+                    }
+                },
+                (Some(_), None) => println!("Ignoring snapshot {}: missing appfs hash", name),
+            }
+        }
+        println!("{} runtime snapshots and {} function snapshots loaded",
+                 cache.runtime_snapshots.len(), cache.function_snapshots.len());
+
+        cache
+    }
+
+    // Return the snapshot to boot a VM of `function` with `mem` MB and `vcpu_count` vCPUs from,
+    // if there is a matching one, and whether it is a function snapshot. Function snapshots are
+    // preferred over runtime snapshots as they already have the function's code loaded.
+    // A snapshot with the right memory size but a different vCPU count than the CPU policy
+    // gives VMs is reported, once, as it can't be used.
+    pub fn find(&mut self, function: &config::FunctionConfig, mem: usize, vcpu_count: u64) -> Option<(&PathBuf, bool)> {
+        let mismatched = &mut self.mismatched;
+        let mut matches = |dir: &PathBuf, metadata: &SnapshotMetadata| {
+            if metadata.mem_size_mib != mem {
+                return false;
+            }
+            if metadata.vcpu_count != vcpu_count && mismatched.insert((dir.clone(), vcpu_count)) {
+                println!("Not using snapshot {}: it has {} vCPUs, but VMs of {} get {} with the current CPU policy",
+                         dir.display(), metadata.vcpu_count, function.name, vcpu_count);
+            }
+            metadata.vcpu_count == vcpu_count
+        };
+        if let Some((dir, metadata)) = self.function_snapshots.get(&function.name) {
+            if matches(dir, metadata) {
+                return Some((dir, true));
+            }
+        }
+        self.runtime_snapshots.get(&snapshot::runtime_snapshot_name(&function.runtime, mem))
+            .and_then(|(dir, metadata)| if matches(dir, metadata) { Some((dir, false)) } else { None })
+    }
+}
@@ -0,0 +1,70 @@
+// Collects the metrics VMs' VMMs flush to `<log_dir>/<vm id>-<function>.metrics`, for
+// `metrics::Metrics`. Every flush appends a JSON line with the counters' increments since the
+// previous flush.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+pub const EXTENSION: &str = "metrics";
+
+pub struct VmmMetricsCollector {
+    dir: PathBuf,
+    offsets: BTreeMap<PathBuf, u64>, // how much of each file has been collected
+}
+
+impl VmmMetricsCollector {
+    pub fn new<P: AsRef<Path>>(dir: P) -> VmmMetricsCollector {
+        VmmMetricsCollector {
+            dir: dir.as_ref().to_path_buf(),
+            offsets: BTreeMap::new(),
+        }
+    }
+
+    // All lines flushed since the last call, with the id of their VM
+    pub fn collect(&mut self) -> Vec<(u32, Value)> {
+        let mut samples = Vec::new();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Couldn't read VMM metrics directory {}: {}", self.dir.display(), e);
+                return samples;
+            }
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().map(|ext| ext != EXTENSION).unwrap_or(true) {
+                continue;
+            }
+            let id = path.file_stem()
+                .and_then(|stem| stem.to_string_lossy().split('-').next().map(|id| id.parse::<u32>()));
+            let id = match id {
+                Some(Ok(id)) => id,
+                _ => continue,
+            };
+
+            let offset = self.offsets.get(&path).cloned().unwrap_or(0);
+            let mut data = String::new();
+            let read = File::open(&path).and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_to_string(&mut data)
+            });
+            if let Err(e) = read {
+                println!("Couldn't read VMM metrics {}: {}", path.display(), e);
+                continue;
+            }
+
+            // the last line may still be being written
+            let complete = data.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            for line in data[..complete].lines().filter(|l| !l.is_empty()) {
+                match serde_json::from_str(line) {
+                    Ok(sample) => samples.push((id, sample)),
+                    Err(e) => println!("Invalid VMM metrics line in {}: {}", path.display(), e),
+                }
+            }
+            self.offsets.insert(path, offset + complete as u64);
+        }
+        samples
+    }
+}
@@ -0,0 +1,364 @@
+// Workload generation for `controller gen-workload`.
+//
+// Reads the YAML schema of workloads/generator.py and writes `request::Request`s as JSON lines,
+// sorted by timestamp. Each function has an arrival process, by default Poisson with a mean
+// inter-arrival time of `mu` ms. Every (function, user) pair is an independent stream, unless
+// the function draws user ids from a Zipf distribution, in which case the function is a single
+// stream `users` times as fast.
+//
+// Streams are generated lazily and merged, so memory use only depends on the number of streams.
+use std::collections::BinaryHeap;
+use std::cmp::{Ordering, Reverse};
+use std::f64::consts::PI;
+use std::io::{self, Write};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::request::Request;
+
+#[derive(Debug, Deserialize)]
+pub struct WorkloadConfig {
+    pub start_time: u64, // ms
+    pub end_time: u64,
+    pub num_users: u32,
+    pub functions: Vec<FunctionWorkload>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FunctionWorkload {
+    pub name: String,
+    pub mu: f64, // mean inter-arrival time in ms, per user
+    #[serde(default)]
+    pub arrival: Arrival,
+    #[serde(default)]
+    pub users: Option<u32>, // overrides num_users
+    #[serde(default)]
+    pub zipf: Option<f64>,  // exponent of the user id distribution, uniform streams if unset
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    #[serde(default)]
+    pub end_time: Option<u64>,
+}
+
+// How the inter-arrival times of a stream are distributed around `mu`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arrival {
+    Poisson,
+    // Markov-modulated Poisson: the stream stays in each state for an exponentially distributed
+    // time with mean `duration` ms, with requests `rate` times as frequent as usual, then moves
+    // on to the next state
+    Mmpp(Vec<MmppState>),
+    // Rate varying as 1 + amplitude * sin(2 pi t / period), t in ms since start_time
+    Diurnal { period: f64, amplitude: f64 },
+    // `mu` changes to each step's `mu` at its time (in ms, absolute). Steps are in order.
+    Step(Vec<Step>),
+    // Pareto distributed inter-arrival times with shape `alpha` (> 1) and mean `mu`
+    Pareto { alpha: f64 },
+}
+
+impl Default for Arrival {
+    fn default() -> Arrival {
+        Arrival::Poisson
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MmppState {
+    pub rate: f64,
+    pub duration: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    pub at: u64,
+    pub mu: f64,
+}
+
+// Write the requests of `config` to `out` and return how many there were
+pub fn generate<W: Write>(config: &WorkloadConfig, seed: u64, out: W) -> io::Result<u64> {
+    let mut streams = Vec::new();
+    for function in config.functions.iter() {
+        let users = function.users.unwrap_or(config.num_users);
+        if users == 0 {
+            continue;
+        }
+        let start = function.start_time.unwrap_or(config.start_time);
+        let end = function.end_time.unwrap_or(config.end_time);
+        let user_ids = match function.zipf {
+            Some(s) => vec![UserIds::Zipf(zipf_cdf(users, s))],
+            None => (0..users).map(UserIds::Fixed).collect(),
+        };
+        // a Zipf stream carries the requests of all users
+        let mu = if function.zipf.is_some() { function.mu / users as f64 } else { function.mu };
+        for user_ids in user_ids {
+            let rng = StdRng::seed_from_u64(seed.wrapping_add((streams.len() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+            streams.push(Stream::new(&function.name, mu, function.arrival.clone(), user_ids, start, end, rng));
+        }
+    }
+
+    // k-way merge, ties go to the stream listed first
+    let mut heap = BinaryHeap::new();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        if let Some(next) = stream.next() {
+            heap.push(Reverse((next, i)));
+        }
+    }
+    let mut out = io::BufWriter::new(out);
+    let mut count = 0;
+    while let Some(Reverse(((timestamp, user_id), i))) = heap.pop() {
+        let request = Request {
+            timestamp,
+            function: streams[i].function.clone(),
+            payload: json!({"request": 42}),
+            user_id,
+            id: None,
+        };
+        serde_json::to_writer(&mut out, &request)?;
+        out.write_all(b"\n")?;
+        count += 1;
+        if let Some(next) = streams[i].next() {
+            heap.push(Reverse((next, i)));
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+enum UserIds {
+    Fixed(u32),
+    Zipf(Vec<f64>), // cumulative probabilities of user ids 0, 1, ...
+}
+
+fn zipf_cdf(users: u32, s: f64) -> Vec<f64> {
+    let weights: Vec<f64> = (1..=users).map(|k| 1.0 / (k as f64).powf(s)).collect();
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.0;
+    weights.iter().map(|w| { sum += w / total; sum }).collect()
+}
+
+fn exponential(rng: &mut StdRng, mean: f64) -> f64 {
+    -mean * (1.0 - rng.gen::<f64>()).ln()
+}
+
+// Requests of one function, and one user unless user ids are Zipf distributed
+struct Stream {
+    function: String,
+    mu: f64,
+    arrival: Arrival,
+    user_ids: UserIds,
+    start: u64,
+    end: u64,
+    time: f64,       // of the last request, in ms
+    state: usize,    // of an MMPP stream
+    state_end: f64,  // when an MMPP stream leaves `state`
+    rng: StdRng,
+}
+
+impl Stream {
+    fn new(function: &String, mu: f64, arrival: Arrival, user_ids: UserIds, start: u64, end: u64,
+           rng: StdRng) -> Stream {
+        let mut rng = rng;
+        let state_end = match arrival {
+            Arrival::Mmpp(ref states) if !states.is_empty() => start as f64 + exponential(&mut rng, states[0].duration),
+            _ => std::f64::INFINITY,
+        };
+        Stream {
+            function: function.clone(),
+            mu,
+            arrival,
+            user_ids,
+            start,
+            end,
+            time: start as f64,
+            state: 0,
+            state_end,
+            rng,
+        }
+    }
+
+    // Time of the next request, in ms
+    fn next_time(&mut self) -> f64 {
+        let rng = &mut self.rng;
+        match self.arrival {
+            Arrival::Mmpp(ref states) if !states.is_empty() => {
+                // memorylessness lets the stream start over at each state change
+                let mut time = self.time;
+                loop {
+                    let next = time + exponential(rng, self.mu / states[self.state].rate);
+                    if next <= self.state_end {
+                        return next;
+                    }
+                    time = self.state_end;
+                    self.state = (self.state + 1) % states.len();
+                    self.state_end = time + exponential(rng, states[self.state].duration);
+                }
+            },
+            Arrival::Poisson | Arrival::Mmpp(_) => self.time + exponential(rng, self.mu),
+            Arrival::Diurnal { period, amplitude } => {
+                // thinning of a Poisson process at the peak rate
+                let peak = 1.0 + amplitude.abs();
+                let mut time = self.time;
+                loop {
+                    time += exponential(rng, self.mu / peak);
+                    let phase = 2.0 * PI * (time - self.start as f64) / period;
+                    let rate = 1.0 + amplitude * phase.sin();
+                    if time > self.end as f64 || rng.gen::<f64>() * peak < rate {
+                        return time;
+                    }
+                }
+            },
+            Arrival::Step(ref steps) => {
+                let mut time = self.time;
+                loop {
+                    let mu = steps.iter().filter(|s| (s.at as f64) <= time).last().map(|s| s.mu).unwrap_or(self.mu);
+                    let next = time + exponential(rng, mu);
+                    // restart at a step in between, the new mu applies from there
+                    match steps.iter().find(|s| (s.at as f64) > time && (s.at as f64) < next) {
+                        Some(step) => time = step.at as f64,
+                        None => return next,
+                    }
+                }
+            },
+            Arrival::Pareto { alpha } => {
+                let scale = self.mu * (alpha - 1.0) / alpha;
+                self.time + scale / (1.0 - rng.gen::<f64>()).powf(1.0 / alpha)
+            },
+        }
+    }
+
+    // (timestamp, user id) of the next request, until `end`
+    fn next(&mut self) -> Option<(u64, u32)> {
+        self.time = self.next_time();
+        // whole ms, like generator.py
+        let timestamp = self.time.ceil();
+        if timestamp > self.end as f64 {
+            return None;
+        }
+        let user_id = match self.user_ids {
+            UserIds::Fixed(id) => id,
+            UserIds::Zipf(ref cdf) => {
+                let u = self.rng.gen::<f64>();
+                match cdf.binary_search_by(|p| p.partial_cmp(&u).unwrap_or(Ordering::Less)) {
+                    Ok(i) | Err(i) => i.min(cdf.len() - 1) as u32,
+                }
+            },
+        };
+        Some((timestamp as u64, user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::request;
+
+    fn generated(config: &str, seed: u64) -> Vec<Request> {
+        let config: WorkloadConfig = serde_yaml::from_str(config).unwrap();
+        let mut out = Vec::new();
+        let count = generate(&config, seed, &mut out).unwrap();
+        let requests: Vec<Request> = String::from_utf8(out).unwrap().lines()
+            .map(|line| request::parse_json(line.to_string()).unwrap())
+            .collect();
+        assert_eq!(requests.len() as u64, count);
+        requests
+    }
+
+    fn assert_near(count: usize, expected: f64) {
+        assert!((count as f64 - expected).abs() < expected * 0.1,
+                "{} requests instead of about {}", count, expected);
+    }
+
+    const MIXED: &str = "
+start_time: 0
+end_time: 100000
+num_users: 3
+functions:
+  - {name: a, mu: 100}
+  - {name: b, mu: 50, zipf: 1.1, users: 5}
+  - {name: c, mu: 200, arrival: {pareto: {alpha: 1.5}}}
+  - {name: d, mu: 100, arrival: {mmpp: [{rate: 1, duration: 1000}, {rate: 5, duration: 200}]}, start_time: 50000}
+";
+
+    #[test]
+    fn same_seed_same_workload() {
+        let requests = generated(MIXED, 7);
+        let timestamps = |requests: &Vec<Request>| -> Vec<(u64, String, u32)> {
+            requests.iter().map(|r| (r.timestamp, r.function.clone(), r.user_id)).collect()
+        };
+        assert_eq!(timestamps(&generated(MIXED, 7)), timestamps(&requests));
+        assert_ne!(timestamps(&generated(MIXED, 8)), timestamps(&requests));
+    }
+
+    #[test]
+    fn merges_streams_in_order() {
+        let requests = generated(MIXED, 1);
+        assert!(requests.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert!(requests.iter().all(|r| r.timestamp <= 100000));
+        // every function contributes, d only after its own start time
+        for function in ["a", "b", "c", "d"].iter() {
+            assert!(requests.iter().any(|r| r.function == *function));
+        }
+        assert!(requests.iter().filter(|r| r.function == "d").all(|r| r.timestamp >= 50000));
+    }
+
+    #[test]
+    fn user_ids_stay_in_range() {
+        let requests = generated(MIXED, 3);
+        for (function, users) in [("a", 3), ("b", 5), ("c", 3)].iter() {
+            let ids: Vec<u32> = requests.iter().filter(|r| r.function == *function).map(|r| r.user_id).collect();
+            assert!(ids.iter().all(|id| id < users));
+            // each user gets requests
+            assert!((0..*users).all(|user| ids.contains(&user)));
+        }
+        // Zipf favours low user ids
+        let b = |user: u32| requests.iter().filter(|r| r.function == "b" && r.user_id == user).count();
+        assert!(b(0) > b(4));
+    }
+
+    #[test]
+    fn poisson_rate() {
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 2, functions: [{name: a, mu: 100}]}", 1);
+        assert_near(requests.len(), 2.0 * 1000000.0 / 100.0);
+        // Zipf streams carry all users' requests
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 4, functions: [{name: a, mu: 400, zipf: 1.0}]}", 1);
+        assert_near(requests.len(), 4.0 * 1000000.0 / 400.0);
+    }
+
+    #[test]
+    fn pareto_rate() {
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 1,
+                                   functions: [{name: a, mu: 100, arrival: {pareto: {alpha: 2.5}}}]}", 1);
+        assert_near(requests.len(), 1000000.0 / 100.0);
+    }
+
+    #[test]
+    fn mmpp_rate() {
+        // half of the time at 3 times the rate
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 1,
+                                   functions: [{name: a, mu: 100, arrival: {mmpp: [{rate: 1, duration: 2000}, {rate: 3, duration: 2000}]}}]}", 1);
+        assert_near(requests.len(), 2.0 * 1000000.0 / 100.0);
+    }
+
+    #[test]
+    fn diurnal_rate() {
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 1,
+                                   functions: [{name: a, mu: 100, arrival: {diurnal: {period: 100000, amplitude: 0.8}}}]}", 1);
+        // the peaks and troughs cancel out over whole periods
+        assert_near(requests.len(), 1000000.0 / 100.0);
+        let rising = requests.iter().filter(|r| r.timestamp % 100000 < 50000).count();
+        assert_near(rising, (1.0 + 1.6 / PI) * 1000000.0 / 100.0 / 2.0);
+    }
+
+    #[test]
+    fn step_rate() {
+        let requests = generated("{start_time: 0, end_time: 1000000, num_users: 1,
+                                   functions: [{name: a, mu: 100, arrival: {step: [{at: 500000, mu: 50}]}}]}", 1);
+        let before = requests.iter().filter(|r| r.timestamp <= 500000).count();
+        assert_near(before, 500000.0 / 100.0);
+        assert_near(requests.len() - before, 500000.0 / 50.0);
+    }
+}
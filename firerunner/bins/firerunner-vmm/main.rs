@@ -0,0 +1,8 @@
+// Runs a single VM configured by the process that spawned it, see `runner::LaunchMode::Exec`.
+// Not meant to be run by hand: the configuration is read as JSON from stdin and refers to
+// descriptors inherited from the parent.
+extern crate firerunner;
+
+fn main() {
+    firerunner::runner::exec_main()
+}
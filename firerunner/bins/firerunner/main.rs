@@ -1,22 +1,27 @@
 #[macro_use(crate_version, crate_authors)]
 extern crate clap;
-extern crate cgroups;
 extern crate firerunner;
+extern crate serde;
+extern crate serde_yaml;
 
 use std::io::{BufRead, Read, Write};
 use std::path::PathBuf;
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
-use firerunner::runner::VmAppConfig;
+use firerunner::cgroup::{self, CgroupConfig, Cpuset};
+use firerunner::runner::{LaunchMode, VmAppConfig};
+
+mod snapshots;
 
 fn main() {
     let cmd_arguments = App::new("firecracker")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Launch a microvm.")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("kernel")
                 .short("k")
@@ -89,6 +94,32 @@ fn main() {
                 .required(false)
                 .help("if specified creates a snapshot right after runtime is up under the given directory")
         )
+        .arg(
+            Arg::with_name("cgroup_parent")
+                .long("cgroup_parent")
+                .value_name("CGROUP_PARENT")
+                .takes_value(true)
+                .required(false)
+                .default_value(firerunner::cgroup::DEFAULT_PARENT)
+                .help("Parent cgroup, relative to the cgroup root, of the VM's cgroup")
+        )
+        .arg(
+            Arg::with_name("cpus")
+                .long("cpus")
+                .value_name("CPU_LIST")
+                .takes_value(true)
+                .required(false)
+                .help("Pin the VM to these CPUs (e.g. 0-1,4)")
+        )
+        .arg(
+            Arg::with_name("mems")
+                .long("mems")
+                .value_name("NODE_LIST")
+                .takes_value(true)
+                .required(false)
+                .requires("cpus")
+                .help("Keep the VM's memory on these NUMA nodes")
+        )
         .arg(
             Arg::with_name("mem_size")
                  .long("mem_size")
@@ -105,8 +136,99 @@ fn main() {
                  .required(false)
                  .help("Number of vcpus (default is 1)")
         )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Manage VM snapshots")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a snapshot for each runtime/memory combination in a function config")
+                        .arg(
+                            Arg::with_name("kernel")
+                                .short("k")
+                                .long("kernel")
+                                .value_name("KERNEL")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Path the the kernel binary")
+                        )
+                        .arg(
+                            Arg::with_name("command line")
+                                .short("c")
+                                .long("cmd_line")
+                                .value_name("CMD_LINE")
+                                .takes_value(true)
+                                .required(false)
+                                .default_value("quiet console=none reboot=k panic=1 pci=off")
+                                .help("Command line to pass to the kernel")
+                        )
+                        .arg(
+                            Arg::with_name("function config file")
+                                .short("f")
+                                .long("fconfig")
+                                .value_name("FUNCTION_CONFIG_FILE")
+                                .takes_value(true)
+                                .required(true)
+                                .help("YAML file defining functions configurations")
+                        )
+                        .arg(
+                            Arg::with_name("runtimefs dir")
+                                .long("runtimefs_dir")
+                                .value_name("RUNTIMEFS_DIR")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Directory containing all runtimefs images")
+                        )
+                        .arg(
+                            Arg::with_name("appfs dir")
+                                .long("appfs_dir")
+                                .value_name("APPFS_DIR")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Directory containing all appfs images")
+                        )
+                        .arg(
+                            Arg::with_name("snapshot dir")
+                                .long("snapshot_dir")
+                                .value_name("SNAPSHOTS_DIR")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Directory to create the snapshots in")
+                        )
+                        .arg(
+                            Arg::with_name("timeout")
+                                .long("timeout")
+                                .value_name("SECONDS")
+                                .takes_value(true)
+                                .required(false)
+                                .default_value("60")
+                                .help("How long to wait for each VM to reach the snapshot point")
+                        )
+                        .arg(
+                            Arg::with_name("per function")
+                                .long("per_function")
+                                .takes_value(false)
+                                .required(false)
+                                .help("Snapshot each function after its code is loaded instead of each runtime")
+                        )
+                        .arg(
+                            Arg::with_name("debug")
+                                .long("debug")
+                                .takes_value(false)
+                                .required(false)
+                                .help("Whether VMs get to write to stdout")
+                        )
+                )
+        )
         .get_matches();
 
+    if let Some(snapshot_args) = cmd_arguments.subcommand_matches("snapshot") {
+        if let Some(create_args) = snapshot_args.subcommand_matches("create") {
+            snapshots::create(create_args);
+        }
+        return;
+    }
+
     let kernel = cmd_arguments.value_of("kernel").unwrap().to_string();
     let rootfs = [cmd_arguments.value_of("rootfs").unwrap()].iter().collect();
     let appfs = cmd_arguments.value_of("appfs").map(|s| [s].iter().collect());
@@ -115,6 +237,13 @@ fn main() {
     let vcpu_count = cmd_arguments.value_of("vcpu_count").map(|x| x.parse::<u64>().unwrap());
     let load_dir = cmd_arguments.value_of("load_dir").map(PathBuf::from);
     let dump_dir = cmd_arguments.value_of("dump_dir").map(PathBuf::from);
+    let cgroup_parent = cmd_arguments.value_of("cgroup_parent").unwrap();
+    let cpuset = cmd_arguments.value_of("cpus").map(|cpus| Cpuset {
+        cpus: cgroup::parse_list(cpus).expect("Invalid CPU list"),
+        mems: cmd_arguments.value_of("mems")
+            .map(|mems| cgroup::parse_list(mems).expect("Invalid NUMA node list"))
+            .unwrap_or(Vec::new()),
+    });
 
     // It's safe to unwrap here because clap's been provided with a default value
     let instance_id = cmd_arguments.value_of("id").unwrap().to_string();
@@ -144,12 +273,22 @@ fn main() {
         seccomp_level,
         vsock_cid: 42,
         notifier: unsafe{ File::from_raw_fd(notifier) },
-        cpu_share: 1024,
+        cgroup: CgroupConfig {
+            parent: PathBuf::from(cgroup_parent),
+            cpuset,
+            ..CgroupConfig::new(1024)
+        },
+        jail: None,
+        launch_mode: LaunchMode::Fork,
+        log_file: None,
+        vmm_logger: None,
+        scratch: None,
+        drive_limits: None,
         vcpu_count: vcpu_count.unwrap_or(1),
         mem_size_mib,
         load_dir,
         dump_dir,
-    }.run(true, None);
+    }.run(true, None).expect("Failed to launch VM");
 
     // We need to wait for the ready signal from Firecracker
     let data = &mut[0u8; 4usize];
@@ -168,5 +307,5 @@ fn main() {
         app.connection.read_exact(response.as_mut_slice()).expect("Failed to read response");
         println!("{}", String::from_utf8(response).unwrap());
     }
-    app.kill();
+    app.kill().expect("Failed to kill VM");
 }
@@ -0,0 +1,83 @@
+// `firerunner snapshot create`: populate a snapshot directory for every runtime/memory
+// combination (or, with `--per_function`, for every function) in a function config file, in
+// the layout the controller expects for `--snapshot_dir`.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use firerunner::snapshot::{self, SnapshotSpec};
+
+// Only the parts of a function config that matter for runtime snapshots
+#[derive(Debug, Deserialize)]
+struct FunctionConfig {
+    name: String,
+    appfs: PathBuf,
+    vcpus: u64,
+    memory: usize,
+    runtime: String,
+}
+
+pub fn create(args: &ArgMatches) {
+    let kernel = args.value_of("kernel").unwrap().to_string();
+    let cmd_line = args.value_of("command line").unwrap().to_string();
+    let runtimefs_dir = Path::new(args.value_of("runtimefs dir").unwrap());
+    let appfs_dir = Path::new(args.value_of("appfs dir").unwrap());
+    let snapshot_dir = Path::new(args.value_of("snapshot dir").unwrap());
+    let timeout = Duration::from_secs(args.value_of("timeout").unwrap()
+                                          .parse::<u64>().expect("Invalid timeout"));
+    let debug = args.is_present("debug");
+    let per_function = args.is_present("per function");
+
+    let config_file = File::open(args.value_of("function config file").unwrap())
+        .expect("Function config file not found");
+    let functions: Vec<FunctionConfig> = serde_yaml::from_reader(config_file)
+        .expect("Invalid function config file");
+
+    // One snapshot per runtime/memory combination, or per function. For runtime snapshots the
+    // appfs of the first function using it is attached so that the device layout matches what
+    // the controller boots later on, and recorded in the snapshot's metadata.
+    let mut specs = BTreeMap::new();
+    for f in functions {
+        let mut rootfs = runtimefs_dir.join(&f.runtime);
+        rootfs.set_extension("ext4");
+        let spec = SnapshotSpec {
+            function: if per_function { Some(f.name.clone()) } else { None },
+            runtime: f.runtime,
+            kernel: kernel.clone(),
+            cmd_line: cmd_line.clone(),
+            rootfs,
+            appfs: Some(appfs_dir.join(&f.appfs)),
+            mem_size_mib: f.memory,
+            vcpu_count: f.vcpus,
+        };
+        let metadata = spec.metadata().expect("Couldn't read kernel or appfs image");
+        let name = metadata.name();
+        if specs.contains_key(&name) {
+            continue;
+        }
+        println!("{}: using function {} for the appfs", name, f.name);
+        specs.insert(name, (spec, metadata));
+    }
+
+    let mut failed = 0;
+    for (name, (spec, metadata)) in specs.iter() {
+        let dir = snapshot_dir.join(name);
+        println!("Creating snapshot {}", dir.display());
+        match snapshot::create(spec, metadata, &dir, timeout, debug) {
+            Ok(_) => println!("Snapshot {} created", name),
+            Err(e) => {
+                println!("Failed to create snapshot {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        eprintln!("{} of {} snapshots could not be created", failed, specs.len());
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,256 @@
+// Per-VM cgroups on either the legacy (v1) or the unified (v2) cgroup hierarchy.
+//
+// Each VM gets its own cgroup, named after the VM process, under a configurable parent cgroup.
+// On v1 that is one directory per controller (`cpu`, `memory`, `cpuset`), on v2 a single directory.
+// The parent cgroup has to exist and be writable by the controller.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CPU_PERIOD_US: u64 = 100000;
+
+pub const DEFAULT_PARENT: &str = "firecracker";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hierarchy {
+    V1,
+    V2,
+}
+
+impl Hierarchy {
+    pub fn detect() -> Hierarchy {
+        if Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            Hierarchy::V2
+        } else {
+            Hierarchy::V1
+        }
+    }
+}
+
+// CPUs and NUMA nodes a VM is restricted to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cpuset {
+    pub cpus: Vec<usize>,
+    pub mems: Vec<usize>, // empty to use the parent cgroup's nodes
+}
+
+impl Cpuset {
+    pub fn cpus_list(&self) -> String {
+        format_list(&self.cpus)
+    }
+
+    pub fn mems_list(&self) -> String {
+        format_list(&self.mems)
+    }
+}
+
+// Parse a kernel cpu/node list such as "0-3,8,10-11"
+pub fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut res = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds.next()?.parse::<usize>().ok()?;
+        let end = match bounds.next() {
+            Some(end) => end.parse::<usize>().ok()?,
+            None => start,
+        };
+        res.extend(start..=end);
+    }
+    Some(res)
+}
+
+fn format_list(items: &[usize]) -> String {
+    items.iter().map(|i| i.to_string()).collect::<Vec<String>>().join(",")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CgroupConfig {
+    pub parent: PathBuf,              // relative to the hierarchy root
+    pub cpu_share: u64,               // cpu.shares on v1, converted to cpu.weight on v2
+    pub cpu_quota: Option<f64>,       // max number of CPUs worth of time (cpu.cfs_quota_us / cpu.max)
+    pub mem_limit_mib: Option<usize>, // memory.limit_in_bytes / memory.max
+    pub cpuset: Option<Cpuset>,       // cpuset.cpus / cpuset.mems
+}
+
+impl CgroupConfig {
+    pub fn new(cpu_share: u64) -> CgroupConfig {
+        CgroupConfig {
+            parent: PathBuf::from(DEFAULT_PARENT),
+            cpu_share,
+            cpu_quota: None,
+            mem_limit_mib: None,
+            cpuset: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VmCgroup {
+    hierarchy: Hierarchy,
+    paths: Vec<PathBuf>, // one per controller on v1, a single one on v2
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| Error::Cgroup(format!("writing {} to {}: {}", value, path.display(), e)))
+}
+
+fn create_dir(path: &Path) -> Result<()> {
+    match fs::create_dir(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        res => res.map_err(|e| Error::Cgroup(format!("creating {}: {}", path.display(), e))),
+    }
+}
+
+// Same conversion as runc: maps shares [2, 262144] onto weights [1, 10000]
+fn shares_to_weight(shares: u64) -> u64 {
+    let shares = shares.max(2).min(262144);
+    1 + ((shares - 2) * 9999) / 262142
+}
+
+impl VmCgroup {
+    pub fn create(config: &CgroupConfig, name: &str) -> Result<VmCgroup> {
+        let hierarchy = Hierarchy::detect();
+        let root = Path::new(CGROUP_ROOT);
+        let paths = match hierarchy {
+            Hierarchy::V1 => {
+                let mut paths = vec![root.join("cpu").join(&config.parent).join(name)];
+                if config.mem_limit_mib.is_some() {
+                    paths.push(root.join("memory").join(&config.parent).join(name));
+                }
+                if config.cpuset.is_some() {
+                    paths.push(root.join("cpuset").join(&config.parent).join(name));
+                }
+                paths
+            },
+            Hierarchy::V2 => vec![root.join(&config.parent).join(name)],
+        };
+        let cgroup = VmCgroup { hierarchy, paths };
+        if let Err(e) = cgroup.configure(config) {
+            let _ = cgroup.delete();
+            return Err(e);
+        }
+        Ok(cgroup)
+    }
+
+    // The v1 cgroup directory of `controller`
+    fn v1_path(&self, controller: &str) -> &Path {
+        let dir = Path::new(CGROUP_ROOT).join(controller);
+        self.paths.iter().find(|p| p.starts_with(&dir)).expect("cgroup not created for controller")
+    }
+
+    fn configure(&self, config: &CgroupConfig) -> Result<()> {
+        let quota = config.cpu_quota.map(|cpus| (cpus * CPU_PERIOD_US as f64) as u64);
+        let mem_limit = config.mem_limit_mib.map(|mib| ((mib as u64) << 20).to_string());
+
+        match self.hierarchy {
+            Hierarchy::V1 => {
+                let cpu = self.v1_path("cpu");
+                create_dir(cpu)?;
+                write_file(&cpu.join("cpu.shares"), &config.cpu_share.to_string())?;
+                if let Some(quota) = quota {
+                    write_file(&cpu.join("cpu.cfs_period_us"), &CPU_PERIOD_US.to_string())?;
+                    write_file(&cpu.join("cpu.cfs_quota_us"), &quota.to_string())?;
+                }
+                if let Some(mem_limit) = mem_limit {
+                    let memory = self.v1_path("memory");
+                    create_dir(memory)?;
+                    write_file(&memory.join("memory.limit_in_bytes"), &mem_limit)?;
+                }
+                if let Some(ref cpuset) = config.cpuset {
+                    let path = self.v1_path("cpuset");
+                    create_dir(path)?;
+                    // v1 cpusets can't take tasks until both cpus and mems are set
+                    let mems = if cpuset.mems.is_empty() {
+                        let parent = path.parent().unwrap().join("cpuset.mems");
+                        fs::read_to_string(&parent)
+                            .map_err(|e| Error::Cgroup(format!("reading {}: {}", parent.display(), e)))?
+                            .trim().to_string()
+                    } else {
+                        cpuset.mems_list()
+                    };
+                    write_file(&path.join("cpuset.cpus"), &cpuset.cpus_list())?;
+                    write_file(&path.join("cpuset.mems"), &mems)?;
+                }
+            },
+            Hierarchy::V2 => {
+                let path = &self.paths[0];
+                let mut controllers = String::from("+cpu");
+                if mem_limit.is_some() {
+                    controllers.push_str(" +memory");
+                }
+                if config.cpuset.is_some() {
+                    controllers.push_str(" +cpuset");
+                }
+                write_file(&path.parent().unwrap().join("cgroup.subtree_control"), &controllers)?;
+
+                create_dir(path)?;
+                write_file(&path.join("cpu.weight"), &shares_to_weight(config.cpu_share).to_string())?;
+                if let Some(quota) = quota {
+                    write_file(&path.join("cpu.max"), &format!("{} {}", quota, CPU_PERIOD_US))?;
+                }
+                if let Some(mem_limit) = mem_limit {
+                    write_file(&path.join("memory.max"), &mem_limit)?;
+                }
+                if let Some(ref cpuset) = config.cpuset {
+                    write_file(&path.join("cpuset.cpus"), &cpuset.cpus_list())?;
+                    if !cpuset.mems.is_empty() {
+                        write_file(&path.join("cpuset.mems"), &cpuset.mems_list())?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Move a process, with all its threads, into the cgroup
+    pub fn add_task(&self, pid: Pid) -> Result<()> {
+        for path in self.paths.iter() {
+            write_file(&path.join("cgroup.procs"), &pid.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Kill every process in the cgroup, including ones the VM process forked
+    pub fn kill_all(&self) -> Result<()> {
+        let procs = self.paths[0].join("cgroup.procs");
+        let pids = fs::read_to_string(&procs)
+            .map_err(|e| Error::Cgroup(format!("reading {}: {}", procs.display(), e)))?;
+        for pid in pids.lines().filter_map(|pid| pid.trim().parse::<i32>().ok()) {
+            let _ = signal::kill(Pid::from_raw(pid), Signal::SIGKILL);
+        }
+        Ok(())
+    }
+
+    // Remove the cgroup. This fails if any process is still in it, but processes that were just
+    // killed get some time to exit.
+    pub fn delete(&self) -> Result<()> {
+        let mut res = Ok(());
+        for path in self.paths.iter() {
+            let mut retries = 100;
+            loop {
+                match fs::remove_dir(path) {
+                    Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) && retries > 0 => {
+                        retries -= 1;
+                        thread::sleep(Duration::from_millis(10));
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+                    Err(e) => {
+                        res = Err(Error::Cgroup(format!("removing {}: {}", path.display(), e)));
+                        break;
+                    },
+                    Ok(()) => break,
+                }
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,102 @@
+// Control channel between a `VmApp` and the `VmmWrapper` in its VM process.
+//
+// The parent sends a `ControlRequest` and the VM process answers each one with a
+// `ControlResponse`. Both are sent as a 4-byte little endian length followed by JSON.
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use nix::poll::{self, PollFlags, PollFd};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use vmm::VmmData;
+use vmm::vmm_config::machine_config::VmConfig;
+
+use crate::error::{Error, Result};
+use crate::vmm_wrapper::VmmWrapper;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlRequest {
+    GetState,
+    GetConfiguration,
+    Pause,
+    Resume,
+    DumpSnapshot(PathBuf), // directory, as seen by the VM process
+    UpdateDrive { drive_id: String, path_on_host: PathBuf },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Done,
+    State(String),
+    Configuration(VmConfig),
+    Error(String),
+}
+
+pub fn send<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let body = serde_json::to_vec(message).map_err(|e| Error::Control(e.to_string()))?;
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+// Returns `None` if the other end closed the channel
+pub fn receive<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    }
+    let mut body = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| Error::Control(e.to_string()))
+}
+
+// Handle requests from the parent until it or the VMM thread is gone, flushing metrics every
+// `flush_interval` if set.
+pub fn serve(vmm: &mut VmmWrapper, mut control: UnixStream, flush_interval: Option<Duration>) {
+    let timeout = flush_interval.map(|i| i.as_millis() as i32).unwrap_or(-1);
+    loop {
+        let mut fds = [PollFd::new(control.as_raw_fd(), PollFlags::POLLIN)];
+        match poll::poll(&mut fds, timeout) {
+            Ok(0) => {
+                // flushing only fails once the VMM thread is gone
+                if vmm.flush_metrics().is_err() {
+                    return;
+                }
+            },
+            Ok(_) => {
+                let request = match receive(&mut control) {
+                    Ok(Some(request)) => request,
+                    _ => return,
+                };
+                if send(&mut control, &handle(vmm, request)).is_err() {
+                    return;
+                }
+            },
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => (),
+            Err(_) => return,
+        }
+    }
+}
+
+fn handle(vmm: &mut VmmWrapper, request: ControlRequest) -> ControlResponse {
+    let res = match request {
+        ControlRequest::GetState => {
+            return ControlResponse::State(format!("{:?}", vmm.read_shared_info().state));
+        },
+        ControlRequest::GetConfiguration => vmm.get_configuration(),
+        ControlRequest::Pause => vmm.pause(),
+        ControlRequest::Resume => vmm.resume(),
+        ControlRequest::DumpSnapshot(dir) => vmm.dump_snapshot(dir),
+        ControlRequest::UpdateDrive { drive_id, path_on_host } => vmm.update_drive(drive_id, path_on_host),
+    };
+    match res {
+        Ok(VmmData::MachineConfiguration(config)) => ControlResponse::Configuration(config),
+        Ok(_) => ControlResponse::Done,
+        Err(e) => ControlResponse::Error(e.to_string()),
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::io;
+
+use vmm::VmmActionError;
+
+#[derive(Debug)]
+pub enum Error {
+    Fork(nix::Error),
+    Pipe(nix::Error),
+    Wait(nix::Error),
+    Sys(nix::Error),           // any other failed system call
+    Io(io::Error),
+    Cgroup(String),
+    EventFd(sys_util::Error),  // failed to create or signal the VMM's event fd
+    VmmThread,                 // the VMM thread is gone and no longer accepts actions
+    Vmm(VmmActionError),       // the VMM rejected an action
+    Child(String),             // the VM process failed to configure or start the VM
+    ChildExited,               // the VM process exited before reporting its status
+    Control(String),           // invalid request on a VM's control channel
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Fork(e) => write!(f, "failed to fork: {}", e),
+            Error::Pipe(e) => write!(f, "failed to create pipe: {}", e),
+            Error::Wait(e) => write!(f, "failed to wait for VM process: {}", e),
+            Error::Sys(e) => write!(f, "system call failed: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Cgroup(e) => write!(f, "cgroup error: {}", e),
+            Error::EventFd(e) => write!(f, "event fd error: {:?}", e),
+            Error::VmmThread => write!(f, "VMM thread is not running"),
+            Error::Vmm(e) => write!(f, "VMM action failed: {:?}", e),
+            Error::Child(e) => write!(f, "VM process failed: {}", e),
+            Error::ChildExited => write!(f, "VM process exited before reporting its status"),
+            Error::Control(e) => write!(f, "control request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
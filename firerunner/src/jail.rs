@@ -0,0 +1,162 @@
+// Jailer-style isolation of a VM process, modeled after Firecracker's jailer.
+//
+// A jailed VM runs chrooted in its own directory, `<chroot_base>/<pid>/root`, that only contains
+// hard links (or copies) of its images, the device nodes it needs, and bind mounts of its snapshot
+// directories and VMM log files.
+// It gets new mount, pid and network namespaces and runs as an unprivileged user. Setting up the
+// jail requires the VM process to start as root.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::mount::{self, MsFlags};
+use nix::sched::{self, CloneFlags};
+use nix::sys::stat::{self, Mode, SFlag};
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Pid, Uid};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::runner::VmAppConfig;
+
+const KVM_MAJOR: u64 = 10;
+const KVM_MINOR: u64 = 232;
+const VHOST_VSOCK_MAJOR: u64 = 10;
+const VHOST_VSOCK_MINOR: u64 = 241;
+
+// Paths of the VM's files inside the jail
+const KERNEL: &str = "/kernel";
+const ROOTFS: &str = "/rootfs.ext4";
+const APPFS: &str = "/appfs.ext4";
+const SCRATCH: &str = "/scratch.img";
+const LOAD_DIR: &str = "/snapshot";
+const DUMP_DIR: &str = "/dump";
+const VMM_LOG: &str = "/vmm.log";
+const METRICS: &str = "/metrics.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JailConfig {
+    pub chroot_base: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl JailConfig {
+    // The directory holding the jail of the VM process `pid`
+    pub fn jail_dir(&self, pid: Pid) -> PathBuf {
+        self.chroot_base.join(pid.to_string())
+    }
+
+    // Move the calling process into a fresh jail and return `config` with its paths rewritten
+    // to the ones inside the jail. As a new pid namespace only applies to children, this forks:
+    // the calling process stays outside, waits for the jailed process and exits with its status.
+    pub fn enter(&self, mut config: VmAppConfig) -> Result<VmAppConfig> {
+        let root = self.jail_dir(unistd::getpid()).join("root");
+        fs::create_dir_all(root.join("dev"))?;
+
+        link_or_copy(Path::new(&config.kernel), &root.join(&KERNEL[1..]))?;
+        config.kernel = KERNEL.to_string();
+        link_or_copy(&config.rootfs, &root.join(&ROOTFS[1..]))?;
+        config.rootfs = PathBuf::from(ROOTFS);
+        if let Some(appfs) = config.appfs.take() {
+            link_or_copy(&appfs, &root.join(&APPFS[1..]))?;
+            config.appfs = Some(PathBuf::from(APPFS));
+        }
+
+        let uid = Uid::from_raw(self.uid);
+        let gid = Gid::from_raw(self.gid);
+        if let Some(ref mut scratch) = config.scratch {
+            // the only image the jailed VMM writes to
+            unistd::chown(&scratch.path, Some(uid), Some(gid)).map_err(Error::Sys)?;
+            link_or_copy(&scratch.path, &root.join(&SCRATCH[1..]))?;
+            scratch.path = PathBuf::from(SCRATCH);
+        }
+        for &(name, major, minor) in [("kvm", KVM_MAJOR, KVM_MINOR),
+                                      ("vhost-vsock", VHOST_VSOCK_MAJOR, VHOST_VSOCK_MINOR)].iter() {
+            let path = root.join("dev").join(name);
+            stat::mknod(&path, SFlag::S_IFCHR, Mode::S_IRUSR | Mode::S_IWUSR, stat::makedev(major, minor))
+                .map_err(Error::Sys)?;
+            unistd::chown(&path, Some(uid), Some(gid)).map_err(Error::Sys)?;
+        }
+
+        sched::unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNET)
+            .map_err(Error::Sys)?;
+        // keep the bind mounts below from propagating to the host
+        mount::mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)
+            .map_err(Error::Sys)?;
+        if let Some(load_dir) = config.load_dir.take() {
+            bind(&load_dir, &root.join(&LOAD_DIR[1..]))?;
+            config.load_dir = Some(PathBuf::from(LOAD_DIR));
+        }
+        if let Some(dump_dir) = config.dump_dir.take() {
+            fs::create_dir_all(&dump_dir)?;
+            unistd::chown(&dump_dir, Some(uid), Some(gid)).map_err(Error::Sys)?;
+            bind(&dump_dir, &root.join(&DUMP_DIR[1..]))?;
+            config.dump_dir = Some(PathBuf::from(DUMP_DIR));
+        }
+        if let Some(ref mut logger) = config.vmm_logger {
+            // the VMM opens these for writing after dropping privileges
+            unistd::chown(&logger.log_file, Some(uid), Some(gid)).map_err(Error::Sys)?;
+            unistd::chown(&logger.metrics_file, Some(uid), Some(gid)).map_err(Error::Sys)?;
+            bind(&logger.log_file, &root.join(&VMM_LOG[1..]))?;
+            logger.log_file = PathBuf::from(VMM_LOG);
+            bind(&logger.metrics_file, &root.join(&METRICS[1..]))?;
+            logger.metrics_file = PathBuf::from(METRICS);
+        }
+
+        // the jailed process is pid 1 of its namespace, so it can't tell its parent by pid. The
+        // parent writes to this pipe once it is waiting, so the child reads EOF instead if the
+        // parent died before the child asked to be killed along with it.
+        let (alive_reader, alive_writer) = unistd::pipe().map_err(Error::Pipe)?;
+        match unistd::fork().map_err(Error::Fork)? {
+            ForkResult::Parent { child } => {
+                let _ = unistd::close(alive_reader);
+                let _ = unistd::write(alive_writer, &[1]);
+                let _ = unistd::close(alive_writer);
+                let code = match wait::waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, code)) => code,
+                    _ => 1,
+                };
+                std::process::exit(code);
+            },
+            ForkResult::Child => {
+                let _ = unistd::close(alive_writer);
+                // don't outlive the process the controller kills
+                unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+                let mut alive = [0u8; 1];
+                let read = loop {
+                    match unistd::read(alive_reader, &mut alive) {
+                        Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                        read => break read,
+                    }
+                };
+                let _ = unistd::close(alive_reader);
+                if read != Ok(1) {
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        unistd::chroot(&root).map_err(Error::Sys)?;
+        unistd::chdir("/").map_err(Error::Sys)?;
+        unistd::setgroups(&[gid]).map_err(Error::Sys)?;
+        unistd::setgid(gid).map_err(Error::Sys)?;
+        unistd::setuid(uid).map_err(Error::Sys)?;
+        Ok(config)
+    }
+}
+
+// Hard link `src` to `dst`, or copy it if they are on different file systems
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::hard_link(src, dst).or_else(|_| fs::copy(src, dst).map(|_| ()))
+}
+
+// Bind mount a directory or file at `dst`
+fn bind(src: &Path, dst: &Path) -> Result<()> {
+    if src.is_dir() {
+        fs::create_dir_all(dst)?;
+    } else {
+        fs::File::create(dst)?;
+    }
+    mount::mount(Some(src), dst, None::<&str>, MsFlags::MS_BIND, None::<&str>).map_err(Error::Sys)
+}
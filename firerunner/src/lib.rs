@@ -1,11 +1,17 @@
-extern crate cgroups;
 extern crate futures;
 extern crate vmm;
 extern crate sys_util;
 extern crate nix;
+extern crate libc;
+extern crate serde;
+extern crate serde_json;
 
+pub mod cgroup;
+pub mod control;
+pub mod error;
+pub mod jail;
 pub mod runner;
 pub mod vmm_wrapper;
 pub mod vsock;
 pub mod pipe_pair;
-
+pub mod snapshot;
@@ -1,171 +1,594 @@
-use cgroups::{self, Cgroup, cgroup_builder::CgroupBuilder};
 use std::path::PathBuf;
 use std::fs::File;
-use std::os::unix::io::FromRawFd;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 use nix::unistd::{self, Pid, ForkResult};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use vmm::vmm_config::boot_source::BootSourceConfig;
 use vmm::vmm_config::drive::BlockDeviceConfig;
 use vmm::vmm_config::machine_config::VmConfig;
 use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
+use vmm::vmm_config::logger::{LoggerConfig, LoggerLevel};
+use vmm::vmm_config::{RateLimiterConfig, TokenBucketConfig};
 
+use crate::cgroup::{CgroupConfig, VmCgroup};
+use crate::control::{self, ControlRequest, ControlResponse};
+use crate::error::{Error, Result};
+use crate::jail::JailConfig;
 use crate::vmm_wrapper::VmmWrapper;
 use super::pipe_pair::PipePair;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+// How the VM process is created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LaunchMode {
+    Fork,          // fork the caller and run the VMM in the child
+    Exec(PathBuf), // spawn this firerunner-vmm binary
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct VmAppConfig {
     pub instance_id: String,
     pub vsock_cid: u32,
+    #[serde(with = "inherited_fd")]
     pub notifier: File, // write end of a pipe
     pub kernel: String,
     pub rootfs: PathBuf,
     pub appfs: Option<PathBuf>,
     pub cmd_line: String,
     pub seccomp_level: u32,
-    pub cpu_share: u64,
+    pub cgroup: CgroupConfig,
+    pub jail: Option<JailConfig>, // run the VM jailed instead of with the caller's privileges
     pub vcpu_count: u64,
     pub mem_size_mib: Option<usize>,
-    pub load_dir: Option<PathBuf>, // ignored by now
-    pub dump_dir: Option<PathBuf>, // ignored by now
+    pub load_dir: Option<PathBuf>, // boot from the snapshot in this directory
+    pub dump_dir: Option<PathBuf>, // dump a snapshot here when the guest reaches the snapshot point
+    pub launch_mode: LaunchMode,
+    pub log_file: Option<PathBuf>, // where the VM process' stdout and stderr go, deleted unless it crashes
+    pub vmm_logger: Option<VmmLoggerConfig>,
+    pub scratch: Option<ScratchDisk>,
+    pub drive_limits: Option<DriveLimits>, // applied to each drive separately
+}
+
+// An empty writable drive, created as a sparse file by `VmAppConfig::run` and deleted with the
+// `VmApp`. The guest sees it as a raw block device after rootfs and appfs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScratchDisk {
+    pub path: PathBuf,
+    pub size_mib: usize,
+}
+
+// Sustained limits of a drive, in bytes and operations per second. Unset limits are unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DriveLimits {
+    pub bandwidth: Option<u64>,
+    pub ops: Option<u64>,
+}
+
+impl DriveLimits {
+    fn rate_limiter(&self) -> RateLimiterConfig {
+        // buckets refill once a second, so their size is the per-second rate
+        let bucket = |rate: u64| TokenBucketConfig {
+            size: rate,
+            one_time_burst: None,
+            refill_time: 1000,
+        };
+        RateLimiterConfig {
+            bandwidth: self.bandwidth.map(bucket),
+            ops: self.ops.map(bucket),
+        }
+    }
+}
+
+// Where the VMM writes its log and metrics. Both files are created by `VmAppConfig::run`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmmLoggerConfig {
+    pub log_file: PathBuf,     // deleted unless the VM crashes, like `VmAppConfig::log_file`
+    pub metrics_file: PathBuf, // one JSON line per flush, left to the caller
+    pub flush_interval_ms: u64,
+}
+
+// What the firerunner-vmm helper reads from its stdin. The descriptors are inherited from the
+// process that spawned it.
+#[derive(Serialize, Deserialize)]
+struct ExecRequest<C> {
+    config: C,
+    request_reader: RawFd,
+    response_writer: RawFd,
+    status_writer: RawFd,
+    control: RawFd,
+    evict_pid: Option<i32>,
+}
+
+// A status is a 4-byte length followed by an error message, or a length of 0 for success
+fn write_status<W: Write>(writer: &mut W, res: &Result<()>) -> io::Result<()> {
+    match res {
+        Ok(()) => writer.write_all(&0u32.to_le_bytes()),
+        Err(e) => {
+            let msg = e.to_string();
+            writer.write_all(&(msg.len() as u32).to_le_bytes())?;
+            writer.write_all(msg.as_bytes())
+        }
+    }
+}
+
+// Errors reported by the VM process become `Error::Child`, EOF becomes `Error::ChildExited`
+fn read_status<R: Read>(reader: &mut R) -> Result<()> {
+    let mut len = [0u8; 4];
+    if reader.read_exact(&mut len).is_err() {
+        return Err(Error::ChildExited);
+    }
+    let len = u32::from_le_bytes(len);
+    if len == 0 {
+        return Ok(());
+    }
+    let mut msg = vec![0u8; len as usize];
+    reader.read_exact(&mut msg)?;
+    Err(Error::Child(String::from_utf8_lossy(&msg).into_owned()))
+}
+
+// (De)serializes a file as its descriptor number, which is only meaningful to a process that
+// inherited the descriptor
+mod inherited_fd {
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(file: &File, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(file.as_raw_fd())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<File, D::Error> {
+        let fd = RawFd::deserialize(deserializer)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+/// Entry point of the firerunner-vmm helper: read the VM's configuration from stdin and run
+/// the VM until it exits.
+pub fn exec_main() -> ! {
+    let request: ExecRequest<VmAppConfig> = match serde_json::from_reader(io::stdin()) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Invalid VM configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let _ = unistd::close(0);
+    request.config.run_vm_process(None, request.request_reader, request.response_writer,
+                                  request.status_writer, request.control,
+                                  request.evict_pid.map(Pid::from_raw))
 }
 
 #[derive(Debug)]
 pub struct VmApp {
     pub config: VmAppConfig,
-    cgroup_name: PathBuf,
+    cgroup: Option<VmCgroup>,
     pub process: Pid,
     pub connection: PipePair,
+    control: UnixStream,
+    exit_status: Option<WaitStatus>, // set once the process has been waited for, so its pid may have been reused
+    killed: bool,
 }
 
 impl VmApp {
-    pub fn kill(&mut self) {
+    pub fn kill(&mut self) -> Result<()> {
+        if self.exit_status.is_some() {
+            return Ok(());
+        }
+        self.killed = true;
 //        println!("issuing kill signal to process: {}", &self.process);
-        nix::sys::signal::kill(self.process, nix::sys::signal::Signal::SIGKILL);
+        nix::sys::signal::kill(self.process, nix::sys::signal::Signal::SIGKILL).map_err(Error::Sys)?;
 //        println!("waiting for process: {}", &self.process);
+        // a jailed VM runs in a child of the VM process
+        match self.cgroup {
+            Some(ref cgroup) => cgroup.kill_all(),
+            None => Ok(()),
+        }
     }
 
-    pub fn wait(&mut self) {
-        nix::sys::wait::waitpid(self.process, None).expect("Failed to kill child");
+    pub fn wait(&mut self) -> Result<()> {
+        if self.exit_status.is_some() {
+            return Ok(());
+        }
+        let status = nix::sys::wait::waitpid(self.process, None).map_err(Error::Wait)?;
+        self.exit_status = Some(status);
+        Ok(())
+    }
+
+    // Stop the VM's vCPUs, e.g. while it is idle
+    pub fn pause(&mut self) -> Result<()> {
+        self.control(ControlRequest::Pause).map(|_| ())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.control(ControlRequest::Resume).map(|_| ())
+    }
+
+    // Forward a request to the VMM in the VM process. Requests the VMM rejects become
+    // `Error::Control`.
+    pub fn control(&mut self, request: ControlRequest) -> Result<ControlResponse> {
+        control::send(&mut self.control, &request)?;
+        match control::receive(&mut self.control)? {
+            Some(ControlResponse::Error(e)) => Err(Error::Control(e)),
+            Some(response) => Ok(response),
+            None => Err(Error::ChildExited),
+        }
+    }
+
+    // Check whether the process exited without blocking
+    pub fn try_wait(&mut self) -> Result<WaitStatus> {
+        let status = nix::sys::wait::waitpid(self.process, Some(WaitPidFlag::WNOHANG)).map_err(Error::Wait)?;
+        if status != WaitStatus::StillAlive {
+            self.exit_status = Some(status);
+        }
+        Ok(status)
+    }
+
+    // Whether the process exited other than cleanly or by being killed with `kill`
+    pub fn crashed(&self) -> bool {
+        match self.exit_status {
+            Some(WaitStatus::Exited(_, 0)) => false,
+            Some(WaitStatus::Signaled(_, Signal::SIGKILL, _)) => !self.killed,
+            Some(_) => true,
+            None => false,
+        }
     }
 }
 
 impl Drop for VmApp {
     fn drop(&mut self) {
-        self.kill();
-        let v1 = cgroups::hierarchies::V1::new();
-        let cgroup = Cgroup::load(&v1, self.cgroup_name.to_str().unwrap());
-        cgroup.delete();
+        // the process has to be gone before its cgroup can be removed
+        if let Err(e) = self.kill().and_then(|_| self.wait()) {
+            eprintln!("Failed to stop VM process {}: {}", self.process, e);
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Err(e) = cgroup.delete() {
+                eprintln!("Failed to remove cgroup of VM process {}: {}", self.process, e);
+            }
+        }
+        let logs = self.config.log_file.iter()
+            .chain(self.config.vmm_logger.as_ref().map(|logger| &logger.log_file));
+        for log_file in logs {
+            if self.crashed() {
+                eprintln!("VM process {} exited unexpectedly ({:?}), its log is in {}",
+                          self.process, self.exit_status.unwrap(), log_file.display());
+            } else if let Err(e) = std::fs::remove_file(log_file) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("Failed to remove log {}: {}", log_file.display(), e);
+                }
+            }
+        }
+        if let Some(ref scratch) = self.config.scratch {
+            if let Err(e) = std::fs::remove_file(&scratch.path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("Failed to remove scratch disk {}: {}", scratch.path.display(), e);
+                }
+            }
+        }
+        if let Some(ref jail) = self.config.jail {
+            let dir = jail.jail_dir(self.process);
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("Failed to remove jail {}: {}", dir.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn close_all(fds: &[RawFd]) {
+    for fd in fds {
+        let _ = unistd::close(*fd);
+    }
+}
+
+// Create `count` close-on-exec pipes, returned as reader, writer, reader, writer, ...
+fn pipes(count: usize) -> Result<Vec<RawFd>> {
+    let mut fds = Vec::new();
+    for _ in 0..count {
+        match unistd::pipe2(OFlag::O_CLOEXEC) {
+            Ok((reader, writer)) => fds.extend_from_slice(&[reader, writer]),
+            Err(e) => {
+                close_all(&fds);
+                return Err(Error::Pipe(e));
+            }
+        }
     }
+    Ok(fds)
 }
 
 impl VmAppConfig {
-    pub fn run(self, debug: bool, evict: Option<VmApp>) -> VmApp {
-        let (request_reader, request_writer) = nix::unistd::pipe().unwrap();
-        let (response_reader, response_writer) = nix::unistd::pipe().unwrap();
+    /// Launch a process running the VM and return once the VM has started or failed to.
+    /// Depending on `launch_mode`, the process is either a fork of the caller or the
+    /// `firerunner-vmm` helper, which gets its configuration on stdin and inherits its pipes.
+    /// Its output goes to `log_file` if set, otherwise to the caller's stdout if `debug`.
+    /// The process only starts the VMM once the parent placed it in its cgroup, which the parent
+    /// signals by writing to the go pipe (fork) or by sending the configuration (exec).
+    /// Configuration errors in the VM process are reported back over a status pipe: the process
+    /// writes a 4-byte length followed by an error message, or a length of 0 on success.
+    pub fn run(self, debug: bool, evict: Option<VmApp>) -> Result<VmApp> {
+        let mut fds = pipes(3)?;
+        match UnixStream::pair() {
+            Ok((control, control_child)) => {
+                fds.push(control.into_raw_fd());
+                fds.push(control_child.into_raw_fd());
+            },
+            Err(e) => {
+                close_all(&fds);
+                return Err(Error::Io(e));
+            }
+        }
+        let (request_reader, request_writer) = (fds[0], fds[1]);
+        let (response_reader, response_writer) = (fds[2], fds[3]);
+        let (status_reader, status_writer) = (fds[4], fds[5]);
+        let (control, control_child) = (fds[6], fds[7]);
         let evict_pid = evict.map(|e| e.process);
-        match unistd::fork() {
-            Err(_) => panic!("Couldn't fork!!"),
-            Ok(ForkResult::Parent { child, .. }) => {
-                let pid = child.as_raw() as u64;
-                let v1 = cgroups::hierarchies::V1::new();
-                let cgroup_name = std::path::Path::new("firecracker").join(pid.to_string().as_str());
-                let cgroup = CgroupBuilder::new(cgroup_name.to_str().unwrap(), &v1)
-                    .cpu()
-                        .shares(self.cpu_share)
-                        .done()
-                    .build();
-                {
-                    use cgroups::Controller;
-                    let cpus: &cgroups::cpu::CpuController = cgroup.controller_of().unwrap();
-                    cpus.add_task(&(pid.into())).expect("Adding child to Cgroup");
+        let log = match self.log_file {
+            Some(ref path) => match File::create(path) {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    close_all(&fds);
+                    return Err(Error::Io(e));
                 }
-                return VmApp {
-                    config: self,
-                    cgroup_name: cgroup_name.clone(),
-                    process: child,
-                    connection: PipePair {
-                        requests_input: unsafe { File::from_raw_fd(request_writer) },
-                        response_reader: unsafe { File::from_raw_fd(response_reader) },
+            },
+            None => None,
+        };
+        if let Some(ref logger) = self.vmm_logger {
+            let created = File::create(&logger.log_file).and_then(|_| File::create(&logger.metrics_file));
+            if let Err(e) = created {
+                close_all(&fds);
+                return Err(Error::Io(e));
+            }
+        }
+        if let Some(ref scratch) = self.scratch {
+            let created = File::create(&scratch.path)
+                .and_then(|disk| disk.set_len(scratch.size_mib as u64 * 1024 * 1024));
+            if let Err(e) = created {
+                close_all(&fds);
+                return Err(Error::Io(e));
+            }
+        }
+
+        let (child, mut go, go_message) = match self.launch_mode.clone() {
+            LaunchMode::Fork => {
+                let go_fds = pipes(1).map_err(|e| {
+                    close_all(&fds);
+                    e
+                })?;
+                let (go_reader, go_writer) = (go_fds[0], go_fds[1]);
+                match unistd::fork() {
+                    Err(e) => {
+                        close_all(&fds);
+                        close_all(&go_fds);
+                        return Err(Error::Fork(e));
+                    },
+                    Ok(ForkResult::Parent { child, .. }) => {
+                        let _ = unistd::close(go_reader);
+                        (child, unsafe { File::from_raw_fd(go_writer) }, vec![1])
                     },
+                    Ok(ForkResult::Child) => {
+                        close_all(&[request_writer, response_reader, status_reader, control, go_writer]);
+                        let _ = unistd::close(0);
+                        if let Some(ref log) = log {
+                            let _ = unistd::dup2(log.as_raw_fd(), 1);
+                            let _ = unistd::dup2(log.as_raw_fd(), 2);
+                        } else if !debug {
+                            let _ = unistd::close(1);
+                        }
+                        self.run_vm_process(Some(go_reader), request_reader, response_writer,
+                                            status_writer, control_child, evict_pid)
+                    }
                 }
             },
-            Ok(ForkResult::Child) => {
-                // Close all open file descriptors in the child process
-//                for i in 0..2 {
-//                     leave stderr open so we can see panics
-//                    if i == 2 {
-//                        continue;
-//                    }
-//
-//                     stop when close fails (means the file descriptor doesn't exist
-//                    if unistd::close(i).is_err() {
-//                        break;
-//                    }
-//                }
-                unistd::close(0);
-                if !debug {
-                    unistd::close(1);
+            LaunchMode::Exec(vmm) => {
+                let request = ExecRequest {
+                    config: &self,
+                    request_reader,
+                    response_writer,
+                    status_writer,
+                    control: control_child,
+                    evict_pid: evict_pid.map(|p| p.as_raw()),
+                };
+                let spawned = serde_json::to_vec(&request)
+                    .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+                    .and_then(|message| {
+                        let inherited = vec![request_reader, response_writer, status_writer,
+                                             control_child, self.notifier.as_raw_fd()];
+                        let mut command = Command::new(&vmm);
+                        command.stdin(Stdio::piped());
+                        match log {
+                            Some(log) => {
+                                command.stdout(Stdio::from(log.try_clone()?)).stderr(Stdio::from(log));
+                            },
+                            None => {
+                                command.stdout(if debug { Stdio::inherit() } else { Stdio::null() });
+                            },
+                        }
+                        // only the VM's own pipes are inherited, everything else is close-on-exec
+                        unsafe {
+                            command.pre_exec(move || {
+                                for fd in inherited.iter() {
+                                    fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                                        .map_err(|_| io::Error::last_os_error())?;
+                                }
+                                Ok(())
+                            });
+                        }
+                        Ok((command.spawn()?, message))
+                    });
+                match spawned {
+                    Err(e) => {
+                        close_all(&fds);
+                        return Err(e);
+                    },
+                    Ok((mut child, message)) => {
+                        let stdin = child.stdin.take().expect("VMM helper has no stdin");
+                        (Pid::from_raw(child.id() as i32), unsafe { File::from_raw_fd(stdin.into_raw_fd()) }, message)
+                    }
                 }
+            }
+        };
 
+        // the child's ends of the pipes must only be held by the child, otherwise
+        // reading responses never sees EOF once the VM is gone
+        close_all(&[request_reader, response_writer, status_writer, control_child]);
+        let mut status = unsafe { File::from_raw_fd(status_reader) };
 
-                let shared_info = Arc::new(RwLock::new(InstanceInfo {
-                    state: InstanceState::Uninitialized,
-                    id: self.instance_id.clone(),
-                    vmm_version: "0.1".to_string(),
-                    load_dir: self.load_dir,
-                    dump_dir: self.dump_dir,
-                }));
-
-                let mut vmm = VmmWrapper::new(shared_info, self.seccomp_level,
-                                              unsafe { File::from_raw_fd(response_writer) },
-                                              unsafe { File::from_raw_fd(request_reader) },
-                                              self.notifier,
-                                              self.vsock_cid);
-
-                let machine_config = VmConfig{
-                    vcpu_count: Some(self.vcpu_count as u8),
-                    mem_size_mib: self.mem_size_mib,
-                    ..Default::default()
-                };
-                vmm.set_configuration(machine_config).expect("set config");
+        // from here on dropping `app` kills and reaps the child
+        let mut app = VmApp {
+            cgroup: None,
+            process: child,
+            connection: PipePair {
+                requests_input: unsafe { File::from_raw_fd(request_writer) },
+                response_reader: unsafe { File::from_raw_fd(response_reader) },
+            },
+            control: unsafe { UnixStream::from_raw_fd(control) },
+            exit_status: None,
+            killed: false,
+            config: self,
+        };
+        let cgroup = VmCgroup::create(&app.config.cgroup, &child.to_string())?;
+        app.cgroup = Some(cgroup);
+        app.cgroup.as_ref().unwrap().add_task(child)?;
+        go.write_all(&go_message)?;
+        drop(go);
 
-                let boot_config = BootSourceConfig {
-                    kernel_image_path: self.kernel,
-                    boot_args: Some(self.cmd_line),
-                };
-                vmm.set_boot_source(boot_config).expect("bootsource");
-
-                let block_config = BlockDeviceConfig {
-                    drive_id: String::from("rootfs"),
-                    path_on_host: self.rootfs,
-                    is_root_device: true,
-                    is_read_only: true,
-                    partuuid: None,
-                    rate_limiter: None,
-                };
-                vmm.insert_block_device(block_config).expect("Rootfs");
-                if let Some(appfs) = self.appfs {
-                    let block_config = BlockDeviceConfig {
-                        drive_id: String::from("appfs"),
-                        path_on_host: appfs,
-                        is_root_device: false,
-                        is_read_only: true,
-                        partuuid: None,
-                        rate_limiter: None,
-                    };
-                    vmm.insert_block_device(block_config).expect("AppBlk");
-                }
+        match read_status(&mut status) {
+            Ok(()) => Ok(app),
+            Err(Error::ChildExited) => {
+                let _ = app.wait();
+                Err(Error::ChildExited)
+            },
+            Err(e) => {
+                let _ = app.kill();
+                let _ = app.wait();
+                Err(e)
+            }
+        }
+    }
 
+    // Body of the VM process, forked or exec'd: wait for the go signal if there is a go pipe,
+    // set up the jail if configured, start the VMM, report its status and then serve control
+    // requests until the VMM exits.
+    fn run_vm_process(self, go_reader: Option<RawFd>, request_reader: RawFd, response_writer: RawFd,
+                      status_writer: RawFd, control: RawFd, evict_pid: Option<Pid>) -> ! {
+        // the controller may block signals it handles on a dedicated thread
+        let _ = SigSet::empty().thread_set_mask();
 
-                evict_pid.map(|evict_pid| {
-                    nix::sys::wait::waitpid(evict_pid, None);
-                }).unwrap_or(());
-                std::mem::forget(evict_pid);
+        // wait until the parent moved us into our cgroup, so that all VMM threads
+        // inherit it. EOF means the parent gave up on this VM.
+        if let Some(go_reader) = go_reader {
+            let mut go = [0u8; 1];
+            if unsafe { File::from_raw_fd(go_reader) }.read_exact(&mut go).is_err() {
+                std::process::exit(1);
+            }
+        }
 
-                vmm.start_instance().expect("Start");
-                vmm.join();
+        let mut status = unsafe { File::from_raw_fd(status_writer) };
+        let flush_interval = self.vmm_logger.as_ref().map(|logger| Duration::from_millis(logger.flush_interval_ms));
+        let started = match self.jail.clone() {
+            Some(jail) => jail.enter(self),
+            None => Ok(self),
+        }.and_then(|config| config.start_vmm(request_reader, response_writer, evict_pid));
+        let _ = write_status(&mut status, &started.as_ref().map(|_| ()).map_err(|e| Error::Child(e.to_string())));
+        drop(status);
+        match started {
+            Ok(mut vmm) => {
+                control::serve(&mut vmm, unsafe { UnixStream::from_raw_fd(control) }, flush_interval);
+                let _ = vmm.join();
                 std::process::exit(0);
-            }
+            },
+            Err(_) => std::process::exit(1),
         }
     }
+
+    // Configure and start the VM. Runs in the VM process.
+    fn start_vmm(self, request_reader: RawFd, response_writer: RawFd, evict_pid: Option<Pid>) -> Result<VmmWrapper> {
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: self.instance_id.clone(),
+            vmm_version: "0.1".to_string(),
+            load_dir: self.load_dir,
+            dump_dir: self.dump_dir,
+        }));
+
+        let mut vmm = VmmWrapper::new(shared_info, self.seccomp_level,
+                                      unsafe { File::from_raw_fd(response_writer) },
+                                      unsafe { File::from_raw_fd(request_reader) },
+                                      self.notifier,
+                                      self.vsock_cid)?;
+
+        if let Some(logger) = self.vmm_logger {
+            vmm.configure_logger(LoggerConfig {
+                log_fifo: logger.log_file.to_string_lossy().into_owned(),
+                metrics_fifo: logger.metrics_file.to_string_lossy().into_owned(),
+                level: LoggerLevel::Warning,
+                show_level: true,
+                show_log_origin: false,
+                options: serde_json::Value::Array(Vec::new()),
+            })?;
+        }
+
+        let machine_config = VmConfig{
+            vcpu_count: Some(self.vcpu_count as u8),
+            mem_size_mib: self.mem_size_mib,
+            ..Default::default()
+        };
+        vmm.set_configuration(machine_config)?;
+
+        let boot_config = BootSourceConfig {
+            kernel_image_path: self.kernel,
+            boot_args: Some(self.cmd_line),
+        };
+        vmm.set_boot_source(boot_config)?;
+
+        let rate_limiter = self.drive_limits.as_ref().map(DriveLimits::rate_limiter);
+        let block_config = BlockDeviceConfig {
+            drive_id: String::from("rootfs"),
+            path_on_host: self.rootfs,
+            is_root_device: true,
+            is_read_only: true,
+            partuuid: None,
+            rate_limiter: rate_limiter.clone(),
+        };
+        vmm.insert_block_device(block_config)?;
+        if let Some(appfs) = self.appfs {
+            let block_config = BlockDeviceConfig {
+                drive_id: String::from("appfs"),
+                path_on_host: appfs,
+                is_root_device: false,
+                is_read_only: true,
+                partuuid: None,
+                rate_limiter: rate_limiter.clone(),
+            };
+            vmm.insert_block_device(block_config)?;
+        }
+        if let Some(scratch) = self.scratch {
+            let block_config = BlockDeviceConfig {
+                drive_id: String::from("scratch"),
+                path_on_host: scratch.path,
+                is_root_device: false,
+                is_read_only: false,
+                partuuid: None,
+                rate_limiter,
+            };
+            vmm.insert_block_device(block_config)?;
+        }
+
+
+        evict_pid.map(|evict_pid| {
+            let _ = nix::sys::wait::waitpid(evict_pid, None);
+        }).unwrap_or(());
+
+        vmm.start_instance()?;
+        Ok(vmm)
+    }
 }
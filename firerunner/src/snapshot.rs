@@ -0,0 +1,231 @@
+// Creation and bookkeeping of VM snapshots.
+//
+// A snapshot is created by booting a VM with `dump_dir` set. The guest runtime writes the
+// snapshot magic to the snapshot port once it is up, at which point the VMM dumps the VM state
+// under `dump_dir`. The runtime then signals readiness as usual on the notifier pipe, so
+// receiving the ready signal (or the VMM exiting) means the dump is complete.
+//
+// Runtime snapshots are taken before the function's appfs is mounted and are shared by all
+// functions using the same runtime and memory size. Function snapshots are taken after the
+// runtime imported the function's code (the guest is told to do so through the kernel command
+// line) and are only valid for the exact appfs image they were created with.
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::wait::WaitStatus;
+use serde::{Deserialize, Serialize};
+
+use crate::cgroup::CgroupConfig;
+use crate::error::{Error, Result};
+use crate::runner::{LaunchMode, VmApp, VmAppConfig};
+
+/// File, inside a snapshot directory, describing how the snapshot was created
+pub const METADATA_FILE: &str = "snapshot.json";
+
+/// Kernel command line argument telling the guest runtime to take the snapshot after loading
+/// the function instead of before mounting the appfs
+pub const APP_SNAPSHOT_ARG: &str = "snapshot=app";
+
+/// Name of the directory holding the snapshot of `runtime` booted with `mem_size_mib` memory
+pub fn runtime_snapshot_name(runtime: &str, mem_size_mib: usize) -> String {
+    format!("{}-{}", runtime, mem_size_mib)
+}
+
+/// Name of the directory holding the snapshot of `function` with its appfs image loaded
+pub fn function_snapshot_name(function: &str, appfs_hash: u64) -> String {
+    format!("{}-{:016x}", function, appfs_hash)
+}
+
+/// 64-bit FNV-1a hash of the content of an appfs image
+pub fn appfs_hash<P: AsRef<Path>>(appfs: P) -> io::Result<u64> {
+    let mut file = File::open(appfs)?;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for b in &buf[..n] {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotMetadata {
+    pub runtime: String,
+    pub mem_size_mib: usize,
+    pub vcpu_count: u64,
+    pub kernel: PathBuf,
+    pub kernel_size: u64,
+    #[serde(default)]
+    pub function: Option<String>, // set for function snapshots
+    #[serde(default)]
+    pub appfs_hash: Option<u64>,
+    // The appfs attached while the snapshot was taken. Runtime snapshots are taken with the appfs
+    // of whichever function using the runtime came first, only for the device layout.
+    #[serde(default)]
+    pub appfs: Option<PathBuf>,
+}
+
+impl SnapshotMetadata {
+    /// Name of the directory this snapshot belongs in
+    pub fn name(&self) -> String {
+        match (&self.function, self.appfs_hash) {
+            (Some(function), Some(hash)) => function_snapshot_name(function, hash),
+            _ => runtime_snapshot_name(&self.runtime, self.mem_size_mib),
+        }
+    }
+
+    pub fn load(dir: &Path) -> io::Result<SnapshotMetadata> {
+        let file = File::open(dir.join(METADATA_FILE))?;
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn store(&self, dir: &Path) -> io::Result<()> {
+        let file = File::create(dir.join(METADATA_FILE))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// Everything needed to boot the VM a snapshot is taken from
+#[derive(Debug, Clone)]
+pub struct SnapshotSpec {
+    pub function: Option<String>, // take a function snapshot of this function
+    pub runtime: String,
+    pub kernel: String,
+    pub cmd_line: String,
+    pub rootfs: PathBuf,
+    pub appfs: Option<PathBuf>,
+    pub mem_size_mib: usize,
+    pub vcpu_count: u64,
+}
+
+impl SnapshotSpec {
+    fn vm_config(&self, notifier: File, load_dir: Option<PathBuf>, dump_dir: Option<PathBuf>) -> VmAppConfig {
+        let mut cmd_line = self.cmd_line.clone();
+        if self.function.is_some() {
+            cmd_line.push(' ');
+            cmd_line.push_str(APP_SNAPSHOT_ARG);
+        }
+        VmAppConfig {
+            instance_id: self.function.clone().unwrap_or(self.runtime.clone()),
+            vsock_cid: 42,
+            notifier,
+            kernel: self.kernel.clone(),
+            rootfs: self.rootfs.clone(),
+            appfs: self.appfs.clone(),
+            cmd_line,
+            seccomp_level: 0,
+            cgroup: CgroupConfig::new(1024),
+            jail: None,
+            launch_mode: LaunchMode::Fork,
+            log_file: None,
+            vmm_logger: None,
+            scratch: None,
+            drive_limits: None,
+            vcpu_count: self.vcpu_count,
+            mem_size_mib: Some(self.mem_size_mib),
+            load_dir,
+            dump_dir,
+        }
+    }
+
+    /// What the snapshot will be created from. Hashes the appfs of function snapshots, so
+    /// it should only be computed once per snapshot.
+    pub fn metadata(&self) -> Result<SnapshotMetadata> {
+        let kernel = fs::canonicalize(&self.kernel)?;
+        let kernel_size = fs::metadata(&kernel)?.len();
+        let appfs_hash = match (&self.function, &self.appfs) {
+            (Some(_), Some(appfs)) => Some(appfs_hash(appfs)?),
+            (Some(_), None) => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                                   "function snapshots require an appfs"))),
+            (None, _) => None,
+        };
+        Ok(SnapshotMetadata {
+            runtime: self.runtime.clone(),
+            mem_size_mib: self.mem_size_mib,
+            vcpu_count: self.vcpu_count,
+            kernel,
+            kernel_size,
+            function: self.function.clone(),
+            appfs_hash,
+            appfs: self.appfs.clone(),
+        })
+    }
+}
+
+/// Boot a VM from `spec`, dump a snapshot of it into `dir` and verify that the snapshot boots.
+/// `metadata` is `spec.metadata()`, stored along with the snapshot. Any previous content of
+/// `dir` is removed. On failure `dir` is removed as well so that no half-written snapshot is
+/// ever picked up by the controller.
+pub fn create(spec: &SnapshotSpec, metadata: &SnapshotMetadata, dir: &Path, timeout: Duration, debug: bool) -> Result<()> {
+    let res = create_and_verify(spec, metadata, dir, timeout, debug);
+    if res.is_err() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    res
+}
+
+fn create_and_verify(spec: &SnapshotSpec, metadata: &SnapshotMetadata, dir: &Path, timeout: Duration, debug: bool) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+
+    // dump
+    let (mut checker, notifier) = notifier_pipe()?;
+    let mut app = spec.vm_config(notifier, None, Some(dir.to_path_buf())).run(debug, None)?;
+    let dumped = wait_for_ready(&mut checker, &mut app, timeout, true)?;
+    drop(app);
+    if !dumped {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "VM did not reach the snapshot point")));
+    }
+    if fs::read_dir(dir)?.next().is_none() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "VMM did not write a snapshot")));
+    }
+    metadata.store(dir)?;
+
+    // verify
+    let (mut checker, notifier) = notifier_pipe()?;
+    let mut app = spec.vm_config(notifier, Some(dir.to_path_buf()), None).run(debug, None)?;
+    let loaded = wait_for_ready(&mut checker, &mut app, timeout, false)?;
+    drop(app);
+    if !loaded {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "VM did not become ready after loading the snapshot")));
+    }
+
+    Ok(())
+}
+
+fn notifier_pipe() -> Result<(File, File)> {
+    let (checker, notifier) = nix::unistd::pipe().map_err(Error::Pipe)?;
+    Ok(unsafe { (File::from_raw_fd(checker), File::from_raw_fd(notifier)) })
+}
+
+/// Wait up to `timeout` for the VM to signal readiness on the notifier pipe.
+/// Returns `false` if it timed out. If `exit_is_ready` is set, a VMM that exits cleanly on its
+/// own (e.g. right after dumping) counts as ready.
+fn wait_for_ready(checker: &mut File, app: &mut VmApp, timeout: Duration, exit_is_ready: bool) -> Result<bool> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        let mut fds = [PollFd::new(checker.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, 100).map_err(Error::Sys)? > 0 {
+            let mut id = [0u8; 4];
+            checker.read_exact(&mut id)?;
+            return Ok(true);
+        }
+        match app.try_wait()? {
+            WaitStatus::Exited(_, 0) if exit_is_ready => return Ok(true),
+            WaitStatus::StillAlive => (),
+            status => return Err(Error::Child(format!("VMM exited unexpectedly: {:?}", status))),
+        }
+    }
+    Ok(false)
+}
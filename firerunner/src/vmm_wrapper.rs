@@ -3,6 +3,7 @@ use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{channel, Sender};
 use std::thread::JoinHandle;
 use std::fs::File;
+use std::path::PathBuf;
 
 use futures::Future;
 use futures::sync::oneshot;
@@ -12,8 +13,13 @@ use vmm::vmm_config::boot_source::BootSourceConfig;
 use vmm::vmm_config::drive::BlockDeviceConfig;
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::logger::LoggerConfig;
 use sys_util::EventFd;
 
+use crate::error::{Error, Result};
+
+type OutcomeSender = oneshot::Sender<std::result::Result<VmmData, VmmActionError>>;
+
 pub struct VmmWrapper {
     sender: Sender<Box<VmmAction>>,
     event_fd: Rc<EventFd>,
@@ -24,78 +30,88 @@ pub struct VmmWrapper {
 impl VmmWrapper {
 
     pub fn new(shared_info: Arc<RwLock<InstanceInfo>>, seccomp_level: u32,
-               response_writer: File, requests_input: File, notifier: File, notifier_id: u32) -> VmmWrapper {
+               response_writer: File, requests_input: File, notifier: File, notifier_id: u32) -> Result<VmmWrapper> {
             let (sender, receiver) = channel();
-            let event_fd = Rc::new(EventFd::new().expect("Cannot create EventFd"));
+            let event_fd = Rc::new(EventFd::new().map_err(Error::EventFd)?);
 
             let thread_handle =
-                vmm::start_vmm_thread(shared_info.clone(), event_fd.try_clone().expect("Couldn't clone event_fd"), receiver, seccomp_level,
+                vmm::start_vmm_thread(shared_info.clone(), event_fd.try_clone().map_err(Error::EventFd)?, receiver, seccomp_level,
                 Some(response_writer), Some(requests_input), Some(notifier), notifier_id);
 
-            VmmWrapper {
+            Ok(VmmWrapper {
                 sender,
                 event_fd,
                 shared_info,
                 thread_handle,
-            }
+            })
     }
 
     pub fn read_shared_info(&self) -> std::sync::RwLockReadGuard<vmm::vmm_config::instance_info::InstanceInfo> {
         self.shared_info.read().expect("shared_info lock poinsoned")
     }
 
-    pub fn join(self) {
-        self.thread_handle.join().expect("failed to join vmm thread")
+    pub fn join(self) -> Result<()> {
+        self.thread_handle.join().map_err(|_| Error::VmmThread)
     }
 
-    pub fn set_configuration(&mut self, machine_config: VmConfig) -> Result<VmmData, VmmActionError> {
+    // Hand an action to the VMM thread and wait for its outcome
+    fn send_action<F: FnOnce(OutcomeSender) -> VmmAction>(&mut self, action: F) -> Result<VmmData> {
         let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::SetVmConfiguration(machine_config, sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't set configuration");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.map(|i| {
-            i
-        }).wait().expect("set config")
+        self.sender.send(Box::new(action(sync_sender))).map_err(|_| Error::VmmThread)?;
+        self.event_fd.write(1).map_err(Error::EventFd)?;
+        sync_receiver.wait().map_err(|_| Error::VmmThread)?.map_err(Error::Vmm)
     }
 
-    pub fn get_configuration(&mut self) -> Result<VmmData, VmmActionError> {
-        let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::GetVmConfiguration(sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't send");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.wait().unwrap()
+    pub fn set_configuration(&mut self, machine_config: VmConfig) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::SetVmConfiguration(machine_config, sender))
     }
 
-    pub fn set_boot_source(&mut self, config: BootSourceConfig) -> Result<VmmData, VmmActionError> {
-        let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::ConfigureBootSource(config, sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't send");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.wait().unwrap()
+    pub fn get_configuration(&mut self) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::GetVmConfiguration(sender))
     }
 
-    pub fn insert_block_device(&mut self, config: BlockDeviceConfig) -> Result<VmmData, VmmActionError> {
-        let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::InsertBlockDevice(config, sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't send");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.wait().unwrap()
+    pub fn set_boot_source(&mut self, config: BootSourceConfig) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::ConfigureBootSource(config, sender))
     }
 
-    pub fn add_vsock(&mut self, config: VsockDeviceConfig) -> Result<VmmData, VmmActionError> {
-        let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::InsertVsockDevice(config, sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't send");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.wait().unwrap()
+    pub fn insert_block_device(&mut self, config: BlockDeviceConfig) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::InsertBlockDevice(config, sender))
     }
 
+    pub fn add_vsock(&mut self, config: VsockDeviceConfig) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::InsertVsockDevice(config, sender))
+    }
 
-    pub fn start_instance(&mut self) -> Result<VmmData, VmmActionError> {
-        let (sync_sender, sync_receiver) = oneshot::channel();
-        let req = VmmAction::StartMicroVm(sync_sender);
-        self.sender.send(Box::new(req)).map_err(|_| ()).expect("Couldn't send");
-        self.event_fd.write(1).map_err(|_| ()).expect("Failed to signal");
-        sync_receiver.wait().unwrap()
+    // Has to happen before the VM is started
+    pub fn configure_logger(&mut self, config: LoggerConfig) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::ConfigureLogger(config, sender))
+    }
+
+    // Write the metrics accumulated since the last flush to the metrics file as a JSON line
+    pub fn flush_metrics(&mut self) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::FlushMetrics(sender))
+    }
+
+
+    pub fn start_instance(&mut self) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::StartMicroVm(sender))
+    }
+
+    pub fn pause(&mut self) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::PauseVCPUs(sender))
+    }
+
+    pub fn resume(&mut self) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::ResumeVCPUs(sender))
+    }
+
+    pub fn dump_snapshot(&mut self, dir: PathBuf) -> Result<VmmData> {
+        self.send_action(|sender| VmmAction::DumpSnapshot(dir, sender))
+    }
+
+    // Point a drive at a different backing file on the host
+    pub fn update_drive(&mut self, drive_id: String, path_on_host: PathBuf) -> Result<VmmData> {
+        let path_on_host = path_on_host.to_string_lossy().into_owned();
+        self.send_action(|sender| VmmAction::UpdateBlockDevicePath(drive_id, path_on_host, sender))
     }
 }
@@ -6,11 +6,20 @@ const fs = require("fs");
 // this approach relies on that we are currently being executed on cpu 0
 // and that other cpus writes to the port before us
 // since as of now snapshots are created offline, we are fine
-const cpu_count = require("os").cpus().length;
-for (var i = 1; i < cpu_count; i++) {
-    exec(`taskset -c ${i} outl 124 0x3f0`);
+function snapshot() {
+    const cpu_count = require("os").cpus().length;
+    for (var i = 1; i < cpu_count; i++) {
+        exec(`taskset -c ${i} outl 124 0x3f0`);
+    }
+    execSync("taskset -c 0 outl 124 0x3f0");
+}
+
+// function snapshots are taken once the app is loaded, runtime snapshots before mounting it
+const app_snapshot = fs.readFileSync("/proc/cmdline", "utf8").split(/\s+/).includes("snapshot=app");
+
+if (!app_snapshot) {
+    snapshot();
 }
-execSync("taskset -c 0 outl 124 0x3f0");
 
 execSync("mount -r /dev/vdb /srv");
 
@@ -21,12 +30,20 @@ rl = readline.createInterface({
 
 out = fs.createWriteStream('/dev/ttyS1');
 
-// signal Firerunner that we are ready to receive requests
-execSync("outl 126 0x3f0");
+if (!app_snapshot) {
+    // signal Firerunner that we are ready to receive requests
+    execSync("outl 126 0x3f0");
+}
 
 module.paths.push("/srv/node_modules");
 const app = require("/srv/workload");
 
+if (app_snapshot) {
+    snapshot();
+    // signal Firerunner that we are ready to receive requests
+    execSync("outl 126 0x3f0");
+}
+
 rl.on('line', (line) => {
   var hrstart = process.hrtime()
   let req = JSON.parse(line);
@@ -10,19 +10,33 @@ import multiprocessing as mp
 import time
 
 # for snapshot
-for i in range(1, mp.cpu_count()):
-    Popen('taskset -c %d outl 124 0x3f0'%(i), shell=True)
-call('taskset -c 0 outl 124 0x3f0', shell=True)
+def snapshot():
+    for i in range(1, mp.cpu_count()):
+        Popen('taskset -c %d outl 124 0x3f0'%(i), shell=True)
+    call('taskset -c 0 outl 124 0x3f0', shell=True)
+
+# function snapshots are taken once the app is loaded, runtime snapshots before mounting it
+with open('/proc/cmdline', 'r') as cmdline:
+    app_snapshot = 'snapshot=app' in cmdline.read().split()
+
+if not app_snapshot:
+    snapshot()
 
 os.system("mount -r /dev/vdb /srv")
 
 with open('/dev/ttyS1', 'r') as tty, open('/dev/ttyS1', 'w') as out:
-    # signal firerunner we are ready
-    call('outl 126 0x3f0', shell=True)
+    if not app_snapshot:
+        # signal firerunner we are ready
+        call('outl 126 0x3f0', shell=True)
 
     sys.path.append('/srv/package')
     app = imp.load_source('app', '/srv/workload')
 
+    if app_snapshot:
+        snapshot()
+        # signal firerunner we are ready
+        call('outl 126 0x3f0', shell=True)
+
     while True:
         request = json.loads(tty.readline())
         t0 = time.clock()